@@ -1,3 +1,7 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::vm::{format_value, Value};
 
 #[derive(Default, Clone, Copy, Debug)]
 pub struct LineNo {
@@ -5,17 +9,20 @@ pub struct LineNo {
 }
 
 impl LineNo {
-    fn new(value:usize) -> Self {
+    pub fn new(value:usize) -> Self {
         LineNo {
             value
         }
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Op {
     Return, 
     // Constant stored at a particular index or idx
     Constant(usize),
+    // The same for an idx that doesn't fit in the one byte operand a
+    // serialized `Constant` has, the compiler picks between the two
+    ConstantLong(usize),
     Nil, 
     True,
     False,
@@ -24,12 +31,102 @@ pub enum Op {
     Subtract,
     Multiply,
     Divide,
+    // Remainder, with the sign of the dividend like C's fmod
+    Modulo,
+    // Bitwise ops work on the integer value of their number operands, an
+    // operand with a fractional part is a runtime error rather than being
+    // truncated. Shifts take an amount between 0 and 63
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
     Not,
     Equal,
     Greater,
     Less,
     Print,
     Pop,
+    // Index of the string constant holding the global's name
+    DefineGlobal(usize),
+    GetGlobal(usize),
+    SetGlobal(usize),
+    DelGlobal(usize),
+    // Index into the VM's global slots, the VM links GetGlobal and
+    // SetGlobal into these before running a chunk
+    GetGlobalCached(usize),
+    SetGlobalCached(usize),
+    // Stack slot of the local, relative to the current function
+    GetLocal(usize),
+    SetLocal(usize),
+    // Index into the upvalues of the running closure
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    // Wrap the function constant at idx into a closure
+    Closure(usize),
+    // Move the local on top of the stack to the heap, then pop it
+    CloseUpvalue,
+    // Push a new class named by the string constant at idx
+    Class(usize),
+    // Pop a closure and bind it under the named constant to the class below
+    Method(usize),
+    // Copy the methods of the superclass below into the class on top, pop it
+    Inherit,
+    // Pop a superclass and a receiver, push the named method bound to it
+    GetSuper(usize),
+    // Pop that many values into a new list
+    BuildList(u8),
+    // Pop an index and a collection, push the element
+    Index,
+    // Pop a value, an index and a collection, store the value and push it back
+    IndexSet,
+    // Absolute index of the op to continue at
+    Jump(usize),
+    // Same, but only taken when the top of the stack is falsey. Doesn't pop
+    JumpIfFalse(usize),
+    // Jump back to the start of a loop
+    Loop(usize),
+    // Call the value below that many arguments
+    Call(u8),
+    // A call in tail position, it replaces the running frame
+    TailCall(u8),
+}
+
+impl Op {
+    /// The op loading constant `idx`, `ConstantLong` once it outgrows a byte
+    pub fn load_constant(idx: usize) -> Op {
+        if idx <= u8::MAX as usize {
+            Op::Constant(idx)
+        } else {
+            Op::ConstantLong(idx)
+        }
+    }
+
+    /// The offset a jump goes to, `None` for any other op
+    pub fn jump_target(&self) -> Option<usize> {
+        match self {
+            Op::Jump(target) | Op::JumpIfFalse(target) | Op::Loop(target) => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// Where a jump goes, so passes that move ops around can re-point it
+    pub fn jump_target_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Op::Jump(target) | Op::JumpIfFalse(target) | Op::Loop(target) => Some(target),
+            _ => None,
+        }
+    }
+}
+
+/// Tells a closure where to find a variable it captures when it is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Upvalue {
+    // true: `index` is a local slot of the enclosing function,
+    // false: `index` is one of the enclosing function's own upvalues
+    pub is_local: bool,
+    pub index: usize,
 }
 
 #[derive(Default, Clone, Debug)]
@@ -37,6 +134,19 @@ pub struct Function {
     pub arity: u8, 
     pub chunk: Chunk,
     pub name : String,
+    pub upvalues: Vec<Upvalue>,
+}
+
+impl std::fmt::Display for Function {
+    /// `<fn name>`, or `<lambda>` for an anonymous function. The script
+    /// has no name either but never ends up in a constant or a value
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "<lambda>")
+        } else {
+            write!(f, "<fn {}>", self.name)
+        }
+    }
 }
 
 
@@ -44,48 +154,425 @@ pub struct Function {
 #[derive(Debug, Clone)]
 pub enum Constant {
     Number(f64),
-    String(String)
+    String(String),
+    Function(Rc<Function>),
+    // only made by `Chunk::fold_constants`, the compiler loads these
+    // with `True`, `False` and `Nil`
+    Bool(bool),
+    Nil,
 }
 
 impl std::fmt::Display for Constant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Constant::Number(num) => write!(f, "{}", num),
+            Constant::Number(num) => write!(f, "{}", format_value(&Value::Number(*num))),
+            // quoted, unlike when printed, so `"1"` and `1` tell apart
             Constant::String(string)    => write!(f, "\"{}\"", string),
+            Constant::Function(function) => write!(f, "{}", function),
+            Constant::Bool(value)   => write!(f, "{}", format_value(&Value::Bool(*value))),
+            Constant::Nil   => write!(f, "{}", format_value(&Value::Nil)),
         }
     }
 }
 
+/// Size figures of a chunk, see `Chunk::stats`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChunkStats {
+    pub op_count: usize,
+    pub constant_count: usize,
+    pub string_constants: usize,
+    pub number_constants: usize,
+    // how many different source lines the ops came from
+    pub distinct_lines: usize,
+}
+
+/// What the constant pool of a chunk holds, see `Chunk::constant_stats`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConstantStats {
+    pub numbers: usize,
+    pub strings: usize,
+    pub functions: usize,
+    // size of the whole pool, after deduplication, folded bools and nil
+    // included
+    pub total: usize,
+}
+
+impl std::fmt::Display for ConstantStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} constants: {} numbers, {} strings, {} functions",
+            self.total, self.numbers, self.strings, self.functions
+        )
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Chunk {
-    // Code is just Opcode and LineNo
-    pub code: Vec<(Op, LineNo)>,
+    pub code: Vec<Op>,
+    // Source lines of the ops, run length encoded as `(offset of the
+    // first op of the run, line)`. Filled in by `write`, read by `line_at`
+    pub lines: Vec<(usize, usize)>,
     pub constants: Vec<Constant>,
+    // Byte range of the source that produced each op, parallel to `code`.
+    // Empty for chunks that weren't built by the compiler
+    pub spans: Vec<(usize, usize)>,
+    // Column of the token that produced each op, parallel to `code` too
+    pub columns: Vec<usize>,
+    // Index of every string constant, so `add_constant_string` finds an
+    // existing one without scanning the pool. Kept up by `add_constant`
+    pub(crate) string_ids: HashMap<String, usize>,
 }
 
 impl Chunk {
-    pub fn add_constant(&mut self, val:Constant) -> usize {
-        let new_idx = self.constants.len();
-        self.constants.push(val);
-        return new_idx
+    /// Count what is in this chunk, not in the functions defined in it
+    pub fn stats(&self) -> ChunkStats {
+        let lines: std::collections::HashSet<usize> =
+            self.lines.iter().map(|(_, line)| *line).collect();
+
+        let constants = self.constant_stats();
+
+        ChunkStats {
+            op_count: self.code.len(),
+            constant_count: constants.total,
+            string_constants: constants.strings,
+            number_constants: constants.numbers,
+            distinct_lines: lines.len(),
+        }
     }
 
-    /// To add a `String`, we must know if it is already there.
-    /// This methods does that exactly. 
-    fn find_string(&self,to_find: &str ) -> Option<usize> {
-        self.constants.iter().position(|c|{
-            if let Constant::String(value) = c {
-                value == to_find
-            } 
-            else {
-                false
+    /// Count the constants of this chunk by kind
+    pub fn constant_stats(&self) -> ConstantStats {
+        let mut stats = ConstantStats {
+            total: self.constants.len(),
+            ..Default::default()
+        };
+        for constant in self.constants.iter() {
+            match constant {
+                Constant::Number(_) => stats.numbers += 1,
+                Constant::String(_) => stats.strings += 1,
+                Constant::Function(_) => stats.functions += 1,
+                Constant::Bool(_) | Constant::Nil => {},
             }
-        })
+        }
+        stats
+    }
+
+    /// Append `op`, compiled from source line `line`
+    pub fn write(&mut self, op: Op, line: LineNo) {
+        if self.lines.last().map(|(_, last)| *last) != Some(line.value) {
+            self.lines.push((self.code.len(), line.value));
+        }
+        self.code.push(op);
+    }
+
+    /// Drop the ops from offset `len` on, along with their lines, spans
+    /// and columns
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.spans.truncate(len);
+        self.columns.truncate(len);
+        while self.lines.last().is_some_and(|(first, _)| *first >= len) {
+            self.lines.pop();
+        }
+    }
+
+    /// The source line of the op at `offset`
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        if offset >= self.code.len() {
+            return None;
+        }
+        // the run holding `offset` is the last one starting at or before it
+        let runs_before = self.lines.partition_point(|(first, _)| *first <= offset);
+        self.lines.get(runs_before.checked_sub(1)?).map(|(_, line)| *line)
+    }
+
+    /// Map every op offset to the `(offset, line, col)` it came from, for
+    /// debuggers. Ops without a recorded column get col 0
+    pub fn source_map(&self) -> Vec<(usize, usize, usize)> {
+        (0..self.code.len())
+            .map(|offset| {
+                let line = self.line_at(offset).unwrap_or(0);
+                let col = self.columns.get(offset).copied().unwrap_or(0);
+                (offset, line, col)
+            })
+            .collect()
+    }
+
+    /// Peephole pass that drops ops which cancel each other out, in this
+    /// chunk and in every function defined in it.
+    ///
+    /// Only `Negate` `Negate` on a number constant is removed, on anything
+    /// else the first `Negate` may be a runtime error. `Not` `Not` has to
+    /// stay since `!!x` turns `x` into a bool. A `Pop` after a `Print` is
+    /// never redundant, `Print` takes its own operand off the stack so the
+    /// `Pop` is for something else, like a local at the end of a block
+    pub fn optimize(&mut self) {
+        for constant in self.constants.iter_mut() {
+            if let Constant::Function(function) = constant {
+                Rc::make_mut(function).chunk.optimize();
+            }
+        }
+
+        let targets = self.jump_targets();
+
+        let mut keep = vec![true; self.code.len()];
+        let mut idx = 1;
+        while idx + 1 < self.code.len() {
+            let cancels = matches!(self.constant_load(idx - 1), Some(Constant::Number(_)))
+                && matches!((&self.code[idx], &self.code[idx + 1]), (Op::Negate, Op::Negate));
+
+            // a jump landing on either would negate some other value
+            if cancels && !targets.contains(&idx) && !targets.contains(&(idx + 1)) {
+                keep[idx] = false;
+                keep[idx + 1] = false;
+                idx += 2;
+            } else {
+                idx += 1;
+            }
+        }
+
+        self.remove_ops(&keep);
+    }
+
+    /// Work out ops whose operands are all constants at compile time, like
+    /// `2 * 3 + 4` into a single load of `10` or `1 < 2` into `true`, in
+    /// this chunk and in every function defined in it. `and` and `or` with
+    /// a constant left operand are cut down to the operand that is the
+    /// result, so `true and false` is `false`. Ops whose result is a
+    /// runtime error, like `1 < "a"`, are left for the VM to report
+    pub fn fold_constants(&mut self) {
+        for constant in self.constants.iter_mut() {
+            if let Constant::Function(function) = constant {
+                Rc::make_mut(function).chunk.fold_constants();
+            }
+        }
+
+        // every round folds the innermost operators, `1 == 2 == false`
+        // takes two
+        loop {
+            let targets = self.jump_targets();
+
+            let mut keep = vec![true; self.code.len()];
+            let mut idx = 2;
+            while idx < self.code.len() {
+                // a jump landing on the right operand or the operator
+                // would skip the ops we fold away
+                let landed_on = targets.contains(&(idx - 1)) || targets.contains(&idx);
+                let folded = match (self.constant_load(idx - 2), self.constant_load(idx - 1)) {
+                    (Some(a), Some(b)) if !landed_on => fold_binary(&self.code[idx], &a, &b),
+                    _ => None,
+                };
+
+                match folded {
+                    Some(value) => {
+                        let value_idx = self.add_folded(value);
+                        self.code[idx] = Op::load_constant(value_idx);
+                        keep[idx - 2] = false;
+                        keep[idx - 1] = false;
+                        idx += 3;
+                    },
+                    None => idx += 1,
+                }
+            }
+
+            let folded_binary = keep.iter().any(|kept| !*kept);
+            if folded_binary {
+                self.remove_ops(&keep);
+            }
+            if !self.fold_logic() && !folded_binary {
+                break;
+            }
+        }
+    }
+
+    /// Fold every `and` and `or` whose left operand is a constant load,
+    /// they compile to
+    ///
+    /// ```text
+    /// and: left, JumpIfFalse(end), Pop, right, end:
+    /// or:  left, JumpIfFalse(else), Jump(end), else: Pop, right, end:
+    /// ```
+    ///
+    /// Returns whether anything was folded
+    fn fold_logic(&mut self) -> bool {
+        let mut folded = false;
+        let mut idx = 0;
+        while idx + 2 < self.code.len() {
+            let truthy = match (self.constant_load(idx), &self.code[idx + 1]) {
+                (Some(left), Op::JumpIfFalse(_)) => !matches!(left, Constant::Nil | Constant::Bool(false)),
+                _ => {
+                    idx += 1;
+                    continue;
+                },
+            };
+            let else_target = self.code[idx + 1].jump_target().unwrap_or(0);
+
+            // the range of ops that goes away, everything the left operand
+            // makes dead or pointless
+            let removed = match (&self.code[idx + 2], self.code.get(idx + 3)) {
+                (Op::Jump(end), Some(Op::Pop)) if else_target == idx + 3 => {
+                    if truthy { idx + 1 .. *end } else { idx .. idx + 4 }
+                },
+                (Op::Pop, _) => {
+                    if truthy { idx .. idx + 3 } else { idx + 1 .. else_target }
+                },
+                _ => {
+                    idx += 1;
+                    continue;
+                },
+            };
+
+            // only the left operand may be jumped to from outside, a jump
+            // further in would land in code that is gone
+            let jumped_into = self.code.iter().enumerate().any(|(from, op)| {
+                !removed.contains(&from) && op.jump_target().is_some_and(|to| to > idx && to < removed.end)
+            });
+            if jumped_into {
+                idx += 1;
+                continue;
+            }
+
+            let keep: Vec<bool> = (0..self.code.len()).map(|op| !removed.contains(&op)).collect();
+            self.remove_ops(&keep);
+            folded = true;
+        }
+        folded
+    }
+
+    /// Fold a unary op straight after a constant load into one op: `-5`
+    /// loads `-5` and `!true` is `False`, in this chunk and in every
+    /// function defined in it. `-x` and `-"a"` are left alone
+    pub fn peephole(&mut self) {
+        for constant in self.constants.iter_mut() {
+            if let Constant::Function(function) = constant {
+                Rc::make_mut(function).chunk.peephole();
+            }
+        }
+
+        let targets = self.jump_targets();
+
+        let mut keep = vec![true; self.code.len()];
+        let mut idx = 1;
+        while idx < self.code.len() {
+            // a jump to the unary op would apply it to some other value
+            let operand = match self.constant_load(idx - 1) {
+                Some(operand) if !targets.contains(&idx) => operand,
+                _ => {
+                    idx += 1;
+                    continue;
+                },
+            };
+
+            let folded = match (&self.code[idx], operand) {
+                (Op::Negate, Constant::Number(num)) => Some(Op::load_constant(self.add_constant_number(-num))),
+                (Op::Not, Constant::Nil | Constant::Bool(false)) => Some(Op::True),
+                (Op::Not, _) => Some(Op::False),
+                _ => None,
+            };
+
+            match folded {
+                Some(op) => {
+                    self.code[idx] = op;
+                    keep[idx - 1] = false;
+                    idx += 2;
+                },
+                None => idx += 1,
+            }
+        }
+
+        self.remove_ops(&keep);
+    }
+
+    /// Every offset some jump lands on
+    fn jump_targets(&self) -> HashSet<usize> {
+        self.code.iter().filter_map(Op::jump_target).collect()
+    }
+
+    /// The value the op at `idx` pushes, when it is a constant one
+    fn constant_load(&self, idx: usize) -> Option<Constant> {
+        match self.code[idx] {
+            Op::Constant(idx) | Op::ConstantLong(idx) => match &self.constants[idx] {
+                Constant::Function(_) => None,
+                constant => Some(constant.clone()),
+            },
+            Op::True    => Some(Constant::Bool(true)),
+            Op::False   => Some(Constant::Bool(false)),
+            Op::Nil     => Some(Constant::Nil),
+            _ => None,
+        }
+    }
+
+    /// Add the result of a fold, sharing an equal constant if there is one
+    fn add_folded(&mut self, value: Constant) -> usize {
+        match value {
+            Constant::Number(num) => self.add_constant_number(num),
+            Constant::String(string) => self.add_constant_string(&string),
+            Constant::Bool(value) => {
+                let existing = self.constants.iter().position(|c| matches!(c, Constant::Bool(b) if *b == value));
+                existing.unwrap_or_else(|| self.add_constant(Constant::Bool(value)))
+            },
+            Constant::Nil => {
+                let existing = self.constants.iter().position(|c| matches!(c, Constant::Nil));
+                existing.unwrap_or_else(|| self.add_constant(Constant::Nil))
+            },
+            Constant::Function(_) => self.add_constant(value),
+        }
+    }
+
+    /// Drop every op whose `keep` entry is false and re-point the jumps
+    fn remove_ops(&mut self, keep: &[bool]) {
+        // new_index[i] is where op i ends up, or the first kept op after
+        // it when it is removed. The extra entry is for jumps to the end
+        let mut new_index = Vec::with_capacity(keep.len() + 1);
+        let mut next = 0;
+        for kept in keep {
+            new_index.push(next);
+            if *kept {
+                next += 1;
+            }
+        }
+        new_index.push(next);
+
+        let lines: Vec<usize> = (0..self.code.len())
+            .map(|idx| self.line_at(idx).unwrap_or(0))
+            .collect();
+        let code = std::mem::take(&mut self.code);
+        let spans = std::mem::take(&mut self.spans);
+        let columns = std::mem::take(&mut self.columns);
+        self.lines.clear();
+
+        for (idx, mut op) in code.into_iter().enumerate() {
+            if !keep[idx] {
+                continue;
+            }
+            if let Some(target) = op.jump_target_mut() {
+                *target = new_index[*target];
+            }
+
+            self.write(op, LineNo::new(lines[idx]));
+            if let Some(span) = spans.get(idx) {
+                self.spans.push(*span);
+            }
+            if let Some(col) = columns.get(idx) {
+                self.columns.push(*col);
+            }
+        }
+    }
+
+    pub fn add_constant(&mut self, val:Constant) -> usize {
+        let new_idx = self.constants.len();
+        if let Constant::String(string) = &val {
+            // the first of equal strings is the one found later on
+            self.string_ids.entry(string.clone()).or_insert(new_idx);
+        }
+        self.constants.push(val);
+        new_idx
     }
 
     pub fn add_constant_string(&mut self, to_add: &str) -> usize {
-        if let Some(id)  = self.find_string(to_add) {
-            id
+        if let Some(id) = self.string_ids.get(to_add) {
+            *id
         }
         else {
             self.add_constant(Constant::String(to_add.to_string()))
@@ -101,20 +588,343 @@ impl Chunk {
         }
     }
     
-    /// Checks if the number is already in the `constants` field. 
+    /// Checks if the number is already in the `constants` field.
+    /// Numbers are compared by their bits, so a constant is only shared
+    /// with one written exactly the same: `0.0` and `-0.0` stay apart and
+    /// a NaN finds the NaN with its bits
     fn find_number(&self, to_find: f64) -> Option<usize> {
         self.constants.iter().position(|num| {
             if let Constant::Number(value) = num {
-                // If the difference between the two numbers is so low
-                // that it is the lowest value that can be represented by
-                // our systems then they are almost the same.
-                // This prevents us to unnecessarily compare the float numbers
-
-                (to_find - *value).abs() < f64::EPSILON
+                value.to_bits() == to_find.to_bits()
             }
             else {
                 false
             }
         })
     }
-}
\ No newline at end of file
+}
+
+/// What `op` makes of the constants `a` and `b`, None when it isn't a
+/// binary op or the VM would raise an error. Equality follows the VM
+fn fold_binary(op: &Op, a: &Constant, b: &Constant) -> Option<Constant> {
+    use Constant::*;
+
+    let result = match (op, a, b) {
+        (Op::Equal, Nil, Nil) => Bool(true),
+        (Op::Equal, Bool(a), Bool(b)) => Bool(a == b),
+        (Op::Equal, Number(a), Number(b)) => Bool(a == b),
+        (Op::Equal, String(a), String(b)) => Bool(a == b),
+        (Op::Equal, _, _) => Bool(false),
+        (Op::Add, Number(a), Number(b)) => Number(a + b),
+        (Op::Add, String(a), String(b)) => String(format!("{}{}", a, b)),
+        (Op::Subtract, Number(a), Number(b)) => Number(a - b),
+        (Op::Multiply, Number(a), Number(b)) => Number(a * b),
+        // dividing by zero stays for the VM, which decides what it means
+        (Op::Divide | Op::Modulo, Number(_), Number(b)) if *b == 0.0 => return None,
+        (Op::Divide, Number(a), Number(b)) => Number(a / b),
+        (Op::Modulo, Number(a), Number(b)) => Number(a % b),
+        (Op::Greater, Number(a), Number(b)) => Bool(a > b),
+        (Op::Less, Number(a), Number(b)) => Bool(a < b),
+        _ => return None,
+    };
+    Some(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::scanner::Scanner;
+
+    fn compile_source(source: &str) -> Function {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(source.to_string());
+        compile(scanner.tokens().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn optimize_drops_double_negate() {
+        let mut chunk = compile_source("--5;").chunk;
+        chunk.optimize();
+
+        assert!(matches!(chunk.code[..2], [Op::Constant(_), Op::Pop]));
+        assert!(matches!(chunk.constant_load(0), Some(Constant::Number(n)) if n == 5.0));
+        assert_eq!(chunk.spans.len(), chunk.code.len());
+    }
+
+    #[test]
+    fn optimize_keeps_negates_that_can_fail() {
+        // `-"a"` is a runtime error the optimized code has to raise too
+        for source in ["--x;", "--\"a\";", "--nil;"] {
+            let mut chunk = compile_source(source).chunk;
+            let before = chunk.code.clone();
+
+            chunk.optimize();
+            assert_eq!(chunk.code, before, "{}", source);
+        }
+    }
+
+    #[test]
+    fn print_pops_its_own_operand() {
+        let chunk = compile_source("print 1; print \"a\";").chunk;
+        assert!(!chunk.code.iter().any(|op| matches!(op, Op::Pop)));
+
+        // the pop after this print is the local going out of scope
+        let mut chunk = compile_source("{ var a = 2; print a; }").chunk;
+        let before = chunk.code.clone();
+        assert!(matches!(before[..4], [Op::Constant(_), Op::GetLocal(1), Op::Print, Op::Pop]));
+
+        chunk.optimize();
+        assert_eq!(chunk.code, before);
+    }
+
+    #[test]
+    fn optimize_keeps_jumps_pointing_at_the_same_ops() {
+        let mut chunk = compile_source("true ? --3 : 2;").chunk;
+        assert!(matches!(chunk.code[1], Op::JumpIfFalse(7)));
+        assert!(matches!(chunk.code[6], Op::Jump(9)));
+
+        chunk.optimize();
+
+        assert!(matches!(
+            chunk.code[..8],
+            [
+                Op::True,
+                Op::JumpIfFalse(5),
+                Op::Pop,
+                Op::Constant(_),
+                Op::Jump(7),
+                Op::Pop,
+                Op::Constant(_),
+                Op::Pop,
+            ]
+        ));
+    }
+
+    #[test]
+    fn source_map_follows_lines_and_columns() {
+        let chunk = compile_source("1 +\n  2;").chunk;
+
+        assert_eq!(
+            chunk.source_map()[..4],
+            [(0, 1, 1), (1, 2, 3), (2, 2, 3), (3, 2, 4)]
+        );
+    }
+
+    #[test]
+    fn stats_counts_ops_constants_and_lines() {
+        let mut chunk = Chunk {
+            constants: vec![
+                Constant::Number(1.0),
+                Constant::String("a".to_string()),
+            ],
+            ..Default::default()
+        };
+        chunk.write(Op::Constant(0), LineNo::new(1));
+        chunk.write(Op::Constant(1), LineNo::new(2));
+
+        assert_eq!(chunk.stats(), ChunkStats {
+            op_count: 2,
+            constant_count: 2,
+            string_constants: 1,
+            number_constants: 1,
+            distinct_lines: 2,
+        });
+    }
+
+    #[test]
+    fn line_table_stores_one_entry_per_run() {
+        let mut chunk = Chunk::default();
+        for (op, line) in [(Op::Nil, 1), (Op::Pop, 1), (Op::True, 3), (Op::Pop, 3), (Op::Return, 4)] {
+            chunk.write(op, LineNo::new(line));
+        }
+
+        assert_eq!(chunk.lines, vec![(0, 1), (2, 3), (4, 4)]);
+        let lines: Vec<Option<usize>> = (0..6).map(|offset| chunk.line_at(offset)).collect();
+        assert_eq!(lines, vec![Some(1), Some(1), Some(3), Some(3), Some(4), None]);
+
+        chunk.truncate(3);
+        assert_eq!(chunk.lines, vec![(0, 1), (2, 3)]);
+        chunk.write(Op::Pop, LineNo::new(3));
+        assert_eq!(chunk.lines, vec![(0, 1), (2, 3)]);
+        assert_eq!(chunk.line_at(3), Some(3));
+    }
+
+    #[test]
+    fn constant_stats_count_the_deduplicated_pool() {
+        let mut chunk = Chunk::default();
+        let first = chunk.add_constant_string("name");
+        let second = chunk.add_constant_string("name");
+        chunk.add_constant_number(1.0);
+        chunk.add_constant_number(1.0);
+
+        assert_eq!(first, second);
+        assert_eq!(chunk.constant_stats(), ConstantStats {
+            numbers: 1,
+            strings: 1,
+            functions: 0,
+            total: 2,
+        });
+        assert_eq!(chunk.constant_stats().to_string(), "2 constants: 1 numbers, 1 strings, 0 functions");
+    }
+
+    #[test]
+    fn numbers_are_deduplicated_by_their_bits() {
+        let mut chunk = Chunk::default();
+
+        // an absolute epsilon merged these
+        assert_ne!(chunk.add_constant_number(1e-17), chunk.add_constant_number(2e-17));
+        assert_ne!(chunk.add_constant_number(0.0), chunk.add_constant_number(-0.0));
+        assert_ne!(chunk.add_constant_number(1e15), chunk.add_constant_number(1e15 + 1.0));
+        // past 2^53 adding 1.0 gives back the same f64, so the same constant
+        assert_eq!(chunk.add_constant_number(1e18), chunk.add_constant_number(1e18 + 1.0));
+        assert_eq!(chunk.add_constant_number(f64::NAN), chunk.add_constant_number(f64::NAN));
+        assert_eq!(chunk.add_constant_number(2.5), chunk.add_constant_number(2.5));
+    }
+
+    #[test]
+    fn strings_are_interned() {
+        let mut chunk = Chunk::default();
+        let names: Vec<String> = (0..5000).map(|n| format!("name{}", n)).collect();
+
+        let first: Vec<usize> = names.iter().map(|name| chunk.add_constant_string(name)).collect();
+        let again: Vec<usize> = names.iter().rev().map(|name| chunk.add_constant_string(name)).collect();
+
+        assert_eq!(chunk.constants.len(), 5000);
+        assert_eq!(first, (0..5000).collect::<Vec<usize>>());
+        assert_eq!(again, (0..5000).rev().collect::<Vec<usize>>());
+        assert!(matches!(&chunk.constants[42], Constant::String(name) if name == "name42"));
+    }
+
+    #[test]
+    fn fold_constants_turns_comparisons_into_bools() {
+        let mut chunk = compile_source("print 1 < 2 == true;").chunk;
+        assert!(matches!(
+            chunk.code[..6],
+            [Op::Constant(_), Op::Constant(_), Op::Less, Op::True, Op::Equal, Op::Print]
+        ));
+
+        chunk.fold_constants();
+
+        assert!(matches!(chunk.code[..2], [Op::Constant(_), Op::Print]));
+        assert!(matches!(chunk.constant_load(0), Some(Constant::Bool(true))));
+        assert_eq!(chunk.spans.len(), chunk.code.len());
+    }
+
+    #[test]
+    fn fold_constants_leaves_runtime_errors_and_variables() {
+        for source in ["1 < \"a\";", "a == 1;", "nil > nil;"] {
+            let mut chunk = compile_source(source).chunk;
+            let before = chunk.code.clone();
+
+            chunk.fold_constants();
+            assert_eq!(chunk.code, before, "{}", source);
+        }
+    }
+
+    #[test]
+    fn fold_constants_respects_jumps_into_the_operands() {
+        // the then branch jumps straight to the `2`, it can't be folded
+        let mut chunk = compile_source("(a ? 1 : 2) == 2;").chunk;
+        let before = chunk.code.clone();
+
+        chunk.fold_constants();
+        assert_eq!(chunk.code, before);
+    }
+
+    #[test]
+    fn fold_constants_computes_arithmetic() {
+        let unfolded = compile_source("2 + 3;").chunk;
+        assert!(matches!(unfolded.code[..3], [Op::Constant(_), Op::Constant(_), Op::Add]));
+
+        let mut folded = unfolded.clone();
+        folded.fold_constants();
+        assert!(matches!(folded.code[..2], [Op::Constant(_), Op::Pop]));
+        assert!(matches!(folded.constant_load(0), Some(Constant::Number(n)) if n == 5.0));
+
+        let mut chunk = compile_source("2 * 3 + 4 - 10 / 4;").chunk;
+        chunk.fold_constants();
+        assert!(matches!(chunk.code[..2], [Op::Constant(_), Op::Pop]));
+        assert!(matches!(chunk.constant_load(0), Some(Constant::Number(n)) if n == 7.5));
+
+        let mut chunk = compile_source("\"a\" + \"b\";").chunk;
+        chunk.fold_constants();
+        assert!(matches!(chunk.constant_load(0), Some(Constant::String(s)) if s == "ab"));
+    }
+
+    #[test]
+    fn fold_constants_cuts_and_or_down_to_their_result() {
+        let cases = [
+            ("true and false;", Some(Constant::Bool(false))),
+            ("false and x;", Some(Constant::Bool(false))),
+            ("nil or 2;", Some(Constant::Number(2.0))),
+            ("\"a\" or x;", Some(Constant::String("a".to_string()))),
+            ("true and x;", None),
+        ];
+        for (source, result) in cases {
+            let mut chunk = compile_source(source).chunk;
+            chunk.fold_constants();
+
+            assert!(matches!(chunk.code[1], Op::Pop), "{}", source);
+            assert!(!chunk.code.iter().any(|op| op.jump_target().is_some()), "{}", source);
+            match result {
+                Some(result) => assert_eq!(chunk.constant_load(0).map(|c| c.to_string()), Some(result.to_string())),
+                None => assert!(matches!(chunk.code[0], Op::GetGlobal(_)), "{}", source),
+            }
+            assert_eq!(chunk.spans.len(), chunk.code.len());
+        }
+
+        let mut chunk = compile_source("print true and false or true and 1;").chunk;
+        chunk.fold_constants();
+        assert!(matches!(chunk.code[..2], [Op::Constant(_), Op::Print]));
+        assert!(matches!(chunk.constant_load(0), Some(Constant::Number(n)) if n == 1.0));
+    }
+
+    #[test]
+    fn fold_constants_leaves_and_or_on_variables() {
+        for source in ["a and true;", "a or false;", "(a ? true : b) and c;"] {
+            let mut chunk = compile_source(source).chunk;
+            let before = chunk.code.clone();
+
+            chunk.fold_constants();
+            assert_eq!(chunk.code, before, "{}", source);
+        }
+    }
+
+    #[test]
+    fn fold_constants_leaves_division_by_zero() {
+        for source in ["1 / 0;", "1 % 0;", "1 + 2 % 0;"] {
+            let mut chunk = compile_source(source).chunk;
+            chunk.fold_constants();
+
+            assert!(chunk.code.iter().any(|op| matches!(op, Op::Divide | Op::Modulo)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn peephole_folds_unary_ops_on_constants() {
+        let mut chunk = compile_source("-5;\n!true;\n!false;\n!nil;").chunk;
+        chunk.peephole();
+
+        assert!(matches!(
+            chunk.code[..8],
+            [Op::Constant(_), Op::Pop, Op::False, Op::Pop, Op::True, Op::Pop, Op::True, Op::Pop]
+        ));
+        assert!(matches!(chunk.constant_load(0), Some(Constant::Number(n)) if n == -5.0));
+        // the folded ops keep the line of the op they replace
+        assert_eq!((0..8).map(|idx| chunk.line_at(idx).unwrap()).collect::<Vec<usize>>(), [1, 1, 2, 2, 3, 3, 4, 4]);
+        assert_eq!(chunk.spans.len(), chunk.code.len());
+    }
+
+    #[test]
+    fn peephole_leaves_unary_ops_on_variables() {
+        for source in ["-x;", "!x;", "-\"a\";"] {
+            let mut chunk = compile_source(source).chunk;
+            let before = chunk.code.clone();
+
+            chunk.peephole();
+            assert_eq!(chunk.code, before, "{}", source);
+        }
+    }
+}
@@ -1,58 +1,159 @@
-use std::fmt::format;
-
 use crate::bytecode::{
     Chunk,
+    Constant,
     Op,
 };
 
 
-/// Takes in a `Chunk` and for each `Op` prints the disassembly 
-/// information of those opcodes with `lineno` and `idx`
+/// Takes in a `Chunk` and for each `Op` returns the disassembly
+/// information of those opcodes with `lineno` and `idx`. Only builds the
+/// lines, nothing is printed
 pub fn dis_code(chunk: &Chunk) -> Vec<String> {
 
     let mut lines: Vec<String> = Vec::new();
-   
-    for (idx, (op, lineno)) in chunk.code.iter().enumerate() {
-        println!("Hello, World!");
-        let formatted_op = match *op {
-            Op::Return  => "OP_RETURN".to_string(),
-            Op::Constant(constant_idx) => {
-                let get_idx_value = match chunk.constants.get(constant_idx) {
-                    Some(value) => value,
-                    None  => panic!("Invalid index value!"),
-                };
-
-                format!(
-                    "OP_CONSTANT {} (idx={})",
-                    get_idx_value,
-                    constant_idx
-                )
-            },
-                Op::Nil     =>  "OP_NIL".to_string(),
-                Op::True    =>  "OP_TRUE".to_string(),
-                Op::False   =>  "OP_FALSE".to_string(),
-                Op::Negate  =>  "OP_NEGATE".to_string(),
-                Op::Add     =>  "OP_ADD".to_string(),
-                Op::Subtract    => "OP_SUBTRACT".to_string(),
-                Op::Multiply    => "OP_MULTIPLY".to_string(),
-                Op::Equal   =>  "OP_EQUAL".to_string(),
-                Op::Greater =>  "OP_GREATER".to_string(),
-                Op::Less    =>  "OP_LESS".to_string(),
-                Op::Divide  => "OP_DIVIDE".to_string(),
-                Op::Pop     => "OP_POP".to_string(),
-                Op::Print   =>  "OP_PRINT".to_string(),
-                Op::Not     =>  "OP_NOT".to_string(),
+    let mut previous_line = None;
+
+    for (idx, op) in chunk.code.iter().enumerate() {
+        let formatted_op = dis_op(chunk, op);
+        let lineno = chunk.line_at(idx).unwrap_or(0);
+
+        // like clox, ops from the same line as the one before get a `|`
+        let line_column = if previous_line == Some(lineno) {
+            "   |".to_string()
+        } else {
+            format!("line {}", lineno)
         };
+        previous_line = Some(lineno);
 
         lines.push(
             format!(
-                "{0: <04}   {1: <50} line {2: <50}",
-                idx, formatted_op, lineno.value
+                "{0: <04}   {1: <50} {2}",
+                idx, formatted_op, line_column
             )
         )
     }
     lines
-}   
+}
+
+/// The disassembly of a single `Op`, without its offset and line
+pub fn dis_op(chunk: &Chunk, op: &Op) -> String {
+    match *op {
+        Op::Return  => "OP_RETURN".to_string(),
+        Op::Constant(constant_idx) | Op::ConstantLong(constant_idx) => {
+            let get_idx_value = match chunk.constants.get(constant_idx) {
+                Some(value) => value,
+                None  => panic!("Invalid index value!"),
+            };
+            let name = match op {
+                Op::ConstantLong(_) => "OP_CONSTANT_LONG",
+                _ => "OP_CONSTANT",
+            };
+
+            format!(
+                "{} {} (idx={})",
+                name,
+                get_idx_value,
+                constant_idx
+            )
+        },
+        Op::Nil     =>  "OP_NIL".to_string(),
+        Op::True    =>  "OP_TRUE".to_string(),
+        Op::False   =>  "OP_FALSE".to_string(),
+        Op::Negate  =>  "OP_NEGATE".to_string(),
+        Op::Add     =>  "OP_ADD".to_string(),
+        Op::Subtract    => "OP_SUBTRACT".to_string(),
+        Op::Multiply    => "OP_MULTIPLY".to_string(),
+        Op::Equal   =>  "OP_EQUAL".to_string(),
+        Op::Greater =>  "OP_GREATER".to_string(),
+        Op::Less    =>  "OP_LESS".to_string(),
+        Op::Divide  => "OP_DIVIDE".to_string(),
+        Op::Modulo  => "OP_MODULO".to_string(),
+        Op::BitAnd  => "OP_BIT_AND".to_string(),
+        Op::BitOr   => "OP_BIT_OR".to_string(),
+        Op::BitXor  => "OP_BIT_XOR".to_string(),
+        Op::BitNot  => "OP_BIT_NOT".to_string(),
+        Op::ShiftLeft   => "OP_SHIFT_LEFT".to_string(),
+        Op::ShiftRight  => "OP_SHIFT_RIGHT".to_string(),
+        Op::Pop     => "OP_POP".to_string(),
+        Op::Print   =>  "OP_PRINT".to_string(),
+        Op::Not     =>  "OP_NOT".to_string(),
+        Op::DefineGlobal(name_idx) => format!(
+            "OP_DEFINE_GLOBAL {} (idx={})",
+            chunk.constants[name_idx],
+            name_idx
+        ),
+        Op::GetGlobal(name_idx) => format!(
+            "OP_GET_GLOBAL {} (idx={})",
+            chunk.constants[name_idx],
+            name_idx
+        ),
+        Op::SetGlobal(name_idx) => format!(
+            "OP_SET_GLOBAL {} (idx={})",
+            chunk.constants[name_idx],
+            name_idx
+        ),
+        Op::Class(name_idx) => format!(
+            "OP_CLASS {} (idx={})",
+            chunk.constants[name_idx],
+            name_idx
+        ),
+        Op::Method(name_idx) => format!(
+            "OP_METHOD {} (idx={})",
+            chunk.constants[name_idx],
+            name_idx
+        ),
+        Op::Inherit => "OP_INHERIT".to_string(),
+        Op::GetSuper(name_idx) => format!(
+            "OP_GET_SUPER {} (idx={})",
+            chunk.constants[name_idx],
+            name_idx
+        ),
+        Op::DelGlobal(name_idx) => format!(
+            "OP_DEL_GLOBAL {} (idx={})",
+            chunk.constants[name_idx],
+            name_idx
+        ),
+        Op::GetGlobalCached(slot)   => format!("OP_GET_GLOBAL_CACHED {}", slot),
+        Op::SetGlobalCached(slot)   => format!("OP_SET_GLOBAL_CACHED {}", slot),
+        Op::GetLocal(slot)  => format!("OP_GET_LOCAL {}", slot),
+        Op::SetLocal(slot)  => format!("OP_SET_LOCAL {}", slot),
+        Op::GetUpvalue(idx) => format!("OP_GET_UPVALUE {}", idx),
+        Op::SetUpvalue(idx) => format!("OP_SET_UPVALUE {}", idx),
+        Op::Closure(function_idx) => {
+            let function = &chunk.constants[function_idx];
+
+            // list what the closure captures, like clox does
+            let captures = match function {
+                Constant::Function(function) => function
+                    .upvalues
+                    .iter()
+                    .map(|upvalue| {
+                        let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                        format!("{} {}", kind, upvalue.index)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                _ => String::new(),
+            };
+
+            format!(
+                "OP_CLOSURE {} (idx={}) [{}]",
+                function,
+                function_idx,
+                captures
+            )
+        },
+        Op::CloseUpvalue    => "OP_CLOSE_UPVALUE".to_string(),
+        Op::BuildList(count)    => format!("OP_BUILD_LIST {}", count),
+        Op::Index   => "OP_INDEX".to_string(),
+        Op::IndexSet    => "OP_INDEX_SET".to_string(),
+        Op::Jump(target)    => format!("OP_JUMP -> {}", target),
+        Op::JumpIfFalse(target) => format!("OP_JUMP_IF_FALSE -> {}", target),
+        Op::Loop(target)    => format!("OP_LOOP -> {}", target),
+        Op::Call(arg_count) => format!("OP_CALL {}", arg_count),
+        Op::TailCall(arg_count) => format!("OP_TAIL_CALL {}", arg_count),
+    }
+}
 
 /// Takes in a `Chunk` and `name` and then proceeds to disassemble the whole
 /// chunk.
@@ -71,9 +172,235 @@ pub fn dis_chunk( chunk: &Chunk, name: &str ) -> String {
 
     lines.push("\n------------ code -----------------".to_string());
     
-    for line in dis_code(&chunk) {
+    for line in dis_code(chunk) {
         lines.push(line);
     } 
 
     lines.join("\n")
-}
\ No newline at end of file
+}
+
+/// `dis_chunk` followed by a summary of the constant pool, for checking
+/// how well constants got deduplicated
+pub fn dis_chunk_with_stats(chunk: &Chunk, name: &str) -> String {
+    format!(
+        "{}\n------------ stats ----------------\n{}",
+        dis_chunk(chunk, name),
+        chunk.constant_stats()
+    )
+}
+
+/// The disassembly of `chunk` as JSON, for tools that would rather not
+/// parse the fixed width text of `dis_chunk`. Looks like
+/// `{"constants": [{"idx", "type", "value"}], "code": [{"idx", "op", "operand", "line"}]}`
+/// where `op` is the `OP_` name and `operand` is null for ops without one
+pub fn dis_chunk_json(chunk: &Chunk) -> String {
+    let constants: Vec<String> = chunk
+        .constants
+        .iter()
+        .enumerate()
+        .map(|(idx, constant)| {
+            let (kind, value) = match constant {
+                Constant::Number(num) if num.is_finite() => ("number", num.to_string()),
+                Constant::Number(num) => ("number", json_string(&num.to_string())),
+                Constant::String(string) => ("string", json_string(string)),
+                Constant::Function(function) => ("function", json_string(&function.name)),
+                Constant::Bool(value) => ("bool", value.to_string()),
+                Constant::Nil => ("nil", "null".to_string()),
+            };
+            format!(r#"{{"idx":{},"type":"{}","value":{}}}"#, idx, kind, value)
+        })
+        .collect();
+
+    let code: Vec<String> = chunk
+        .code
+        .iter()
+        .enumerate()
+        .map(|(idx, op)| {
+            let text = dis_op(chunk, op);
+            let name = text.split(' ').next().unwrap_or_default();
+            let operand = match operand(op) {
+                Some(operand) => operand.to_string(),
+                None => "null".to_string(),
+            };
+            format!(
+                r#"{{"idx":{},"op":"{}","operand":{},"line":{}}}"#,
+                idx, name, operand, chunk.line_at(idx).unwrap_or(0)
+            )
+        })
+        .collect();
+
+    format!(r#"{{"constants":[{}],"code":[{}]}}"#, constants.join(","), code.join(","))
+}
+
+/// The number an op carries, whether a constant index, slot, target or count
+fn operand(op: &Op) -> Option<usize> {
+    match *op {
+        Op::Constant(idx)
+        | Op::ConstantLong(idx)
+        | Op::DefineGlobal(idx)
+        | Op::GetGlobal(idx)
+        | Op::SetGlobal(idx)
+        | Op::DelGlobal(idx)
+        | Op::GetGlobalCached(idx)
+        | Op::SetGlobalCached(idx)
+        | Op::GetLocal(idx)
+        | Op::SetLocal(idx)
+        | Op::GetUpvalue(idx)
+        | Op::SetUpvalue(idx)
+        | Op::Closure(idx)
+        | Op::Class(idx)
+        | Op::Method(idx)
+        | Op::GetSuper(idx)
+        | Op::Jump(idx)
+        | Op::JumpIfFalse(idx)
+        | Op::Loop(idx) => Some(idx),
+        Op::BuildList(count) | Op::Call(count) | Op::TailCall(count) => Some(count as usize),
+        _ => None,
+    }
+}
+
+/// `string` as a quoted JSON string
+fn json_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for c in string.chars() {
+        match c {
+            '"'     => quoted.push_str("\\\""),
+            '\\'    => quoted.push_str("\\\\"),
+            '\n'    => quoted.push_str("\\n"),
+            '\r'    => quoted.push_str("\\r"),
+            '\t'    => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A unified-diff style listing of how `after` differs from `before`.
+/// Ops are compared by their disassembly without offsets, so ops that only
+/// moved show as unchanged. Removed ops start with `-`, added ones with `+`
+pub fn diff_chunks(before: &Chunk, after: &Chunk) -> String {
+    let old: Vec<String> = before.code.iter().map(|op| dis_op(before, op)).collect();
+    let new: Vec<String> = after.code.iter().map(|op| dis_op(after, op)).collect();
+
+    // lcs[i][j] is the longest common run of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec!["--- before".to_string(), "+++ after".to_string()];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(format!("  {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+
+    lines.join("\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::LineNo;
+
+    /// A chunk of `ops`, each with its line, over `constants`
+    fn chunk_of(ops: Vec<(Op, usize)>, constants: Vec<Constant>) -> Chunk {
+        let mut chunk = Chunk {
+            constants,
+            ..Default::default()
+        };
+        for (op, line) in ops {
+            chunk.write(op, LineNo::new(line));
+        }
+        chunk
+    }
+
+    #[test]
+    fn json_disassembly_matches_the_text_one() {
+        let chunk = chunk_of(
+            vec![(Op::Constant(0), 3), (Op::DefineGlobal(1), 3), (Op::Jump(3), 4), (Op::Return, 4)],
+            vec![Constant::Number(1.5), Constant::String("say \"hi\"\n".to_string())],
+        );
+
+        let json = dis_chunk_json(&chunk);
+        assert!(json.starts_with(r#"{"constants":[{"idx":0,"type":"number","value":1.5},{"idx":1,"type":"string","value":"say \"hi\"\n"}],"#));
+        assert!(json.contains(r#"{"idx":2,"op":"OP_JUMP","operand":3,"line":4}"#));
+        assert!(json.contains(r#"{"idx":3,"op":"OP_RETURN","operand":null,"line":4}"#));
+
+        // the op names read back out of the JSON are the ones dis_code lists
+        let json_ops: Vec<&str> = json
+            .split(r#""op":""#)
+            .skip(1)
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect();
+        let text_ops: Vec<String> = dis_code(&chunk)
+            .iter()
+            .map(|line| line.split_whitespace().nth(1).unwrap().to_string())
+            .collect();
+        assert_eq!(json_ops, text_ops);
+    }
+
+    #[test]
+    fn stats_are_appended_on_request() {
+        let chunk = chunk_of(vec![(Op::Constant(0), 1)], vec![Constant::Number(1.0)]);
+
+        assert!(!dis_chunk(&chunk, "").contains("constants:"));
+        assert!(dis_chunk_with_stats(&chunk, "").ends_with("\n1 constants: 1 numbers, 0 strings, 0 functions"));
+    }
+
+    #[test]
+    fn ops_on_the_previous_line_show_a_bar() {
+        let chunk = chunk_of(vec![(Op::Nil, 1), (Op::Pop, 1), (Op::Return, 2)], vec![]);
+
+        let lines = dis_code(&chunk);
+        assert!(lines[0].ends_with(" line 1"));
+        assert!(lines[1].ends_with("    |"));
+        assert!(!lines[1].contains("line"));
+        assert!(lines[2].ends_with(" line 2"));
+    }
+
+    #[test]
+    fn diff_chunks_marks_removed_and_added_ops() {
+        let before = chunk_of(
+            vec![(Op::Constant(0), 1), (Op::Constant(1), 1), (Op::Add, 1), (Op::Print, 1)],
+            vec![Constant::Number(1.0), Constant::Number(2.0)],
+        );
+        // `1 + 2` folded into `3`
+        let after = chunk_of(
+            vec![(Op::Constant(0), 1), (Op::Print, 1)],
+            vec![Constant::Number(3.0)],
+        );
+
+        let diff = diff_chunks(&before, &after);
+        let lines: Vec<&str> = diff.lines().collect();
+
+        assert_eq!(lines, vec![
+            "--- before",
+            "+++ after",
+            "- OP_CONSTANT 1 (idx=0)",
+            "- OP_CONSTANT 2 (idx=1)",
+            "- OP_ADD",
+            "+ OP_CONSTANT 3 (idx=0)",
+            "  OP_PRINT",
+        ]);
+    }
+}
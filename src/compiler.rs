@@ -1,16 +1,2049 @@
 /*
-    compiler.rs: Compiler internals. 
-    TODO
+    compiler.rs: Compiler internals.
+    Single pass compiler, it walks over the tokens from the scanner and
+    emits `Op`s straight into the `Chunk` of the function being compiled.
 */
 
-use crate::bytecode;
-use crate::extensions;
-use crate::scanner;
+use std::rc::Rc;
 
+use crate::bytecode::{
+    Chunk,
+    Constant,
+    Function,
+    LineNo,
+    Op,
+    Upvalue,
+};
+use crate::extensions::Extensions;
+use crate::scanner::{
+    Literal,
+    Token,
+    TokenType,
+};
+
+
+// Locals a single function can have, slot 0 included
+const MAX_LOCALS: usize = 256;
 
 #[derive(Debug)]
 struct Local {
-    name: scanner::Token,
+    name: Token,
     depth: i64,
     is_captured: bool,
-}
\ No newline at end of file
+}
+
+/// Binding power of the operators, lowest to highest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    // bitwise operators bind tighter than comparisons, so
+    // `a & b == c` means `(a & b) == c`
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    /// One level tighter, used for the right operand of left
+    /// associative operators
+    fn next(self) -> Precedence {
+        use Precedence::*;
+
+        match self {
+            None        => Assignment,
+            Assignment  => Or,
+            Or          => And,
+            And         => Equality,
+            Equality    => Comparison,
+            Comparison  => BitOr,
+            BitOr       => BitXor,
+            BitXor      => BitAnd,
+            BitAnd      => Shift,
+            Shift       => Term,
+            Term        => Factor,
+            Factor      => Unary,
+            Unary       => Call,
+            Call | Primary  => Primary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Method,
+    Script,
+}
+
+// The flag tells a handler whether it may consume a trailing `=`
+type ParseFn = fn(&mut Compiler, bool);
+
+struct ParseRule {
+    prefix: Option<ParseFn>,
+    infix: Option<ParseFn>,
+    precedence: Precedence,
+}
+
+impl ParseRule {
+    fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, precedence: Precedence) -> Self {
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+/// A mistake found while compiling, at the token where it was noticed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub message: String,
+    pub line: usize,
+    pub col: usize,
+
+    // ` at 'token'` or ` at end`, for the clox style report
+    pub location: String,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] Error{}: {}", self.line, self.location, self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// The loop `break` and `continue` refer to
+struct LoopContext {
+    // where `continue` jumps back to
+    start: usize,
+
+    // locals deeper than this are popped when leaving the loop early
+    scope_depth: i64,
+
+    // `break` jumps, patched once the end of the loop is known
+    break_jumps: Vec<usize>,
+}
+
+/// The class whose body is being compiled
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Everything the compiler has to remember about the function
+/// it is currently emitting code for
+struct FunctionState {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    scope_depth: i64,
+
+    // innermost loop last, loops never reach across functions
+    loops: Vec<LoopContext>,
+}
+
+impl FunctionState {
+    fn new(function_type: FunctionType, name: String) -> Self {
+        FunctionState {
+            function: Function {
+                name,
+                ..Default::default()
+            },
+            function_type,
+            // Slot 0 belongs to the function being called, so no user
+            // variable can ever resolve to it. In methods it holds `this`
+            locals: vec![Local {
+                name: Compiler::synthetic_token(
+                    TokenType::Identifier,
+                    if function_type == FunctionType::Method { "this" } else { "" },
+                ),
+                depth: 0,
+                is_captured: false,
+            }],
+            scope_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+pub struct Compiler {
+    tokens: Vec<Token>,
+
+    // index of the token we are about to consume
+    current: usize,
+
+    // innermost function is at the end
+    states: Vec<FunctionState>,
+
+    errors: Vec<CompileError>,
+
+    // set after an error so that we don't report a cascade of them
+    panic_mode: bool,
+
+    // first byte of every expression being parsed, innermost last
+    span_starts: Vec<usize>,
+
+    // classes being compiled, innermost last
+    classes: Vec<ClassState>,
+
+    extensions: Extensions,
+}
+
+/// Compile the tokens of a whole script into the implicit top level
+/// function. Takes anything that yields tokens, a `Scanner` included
+pub fn compile(tokens: impl IntoIterator<Item = Token>) -> Result<Function, Vec<CompileError>> {
+    compile_with(tokens, Extensions::default())
+}
+
+/// Like `compile`, but constructs of disabled extensions are errors
+pub fn compile_with(
+    tokens: impl IntoIterator<Item = Token>,
+    extensions: Extensions,
+) -> Result<Function, Vec<CompileError>> {
+    let mut compiler = Compiler::new(tokens.into_iter().collect(), extensions);
+
+    while !compiler.matches(TokenType::Eof) {
+        compiler.declaration();
+    }
+
+    let function = compiler.end_function();
+
+    if compiler.errors.is_empty() {
+        Ok(function)
+    } else {
+        Err(compiler.errors)
+    }
+}
+
+impl Compiler {
+    fn new(mut tokens: Vec<Token>, extensions: Extensions) -> Self {
+        // The parser relies on always finding an Eof at the end
+        if tokens.last().map(|t| t.t_type) != Some(TokenType::Eof) {
+            let line = tokens.last().map_or(1, |t| t.line);
+            let mut eof = Compiler::synthetic_token(TokenType::Eof, "");
+            eof.line = line;
+            tokens.push(eof);
+        }
+
+        Compiler {
+            tokens,
+            current: 0,
+            states: vec![FunctionState::new(FunctionType::Script, String::new())],
+            errors: Vec::new(),
+            panic_mode: false,
+            span_starts: Vec::new(),
+            classes: Vec::new(),
+            extensions,
+        }
+    }
+
+    /// A token that doesn't come from the source
+    fn synthetic_token(t_type: TokenType, lexeme: &str) -> Token {
+        Token {
+            t_type,
+            lexeme: lexeme.to_string(),
+            literal: None,
+            line: 0,
+            col: 0,
+            start_col: 0,
+            start_byte: 0,
+            end_byte: 0,
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Declarations and statements
+    // ------------------------------------------------------------------
+
+    fn declaration(&mut self) {
+        if self.matches(TokenType::Class) {
+            self.class_declaration();
+        } else if self.matches(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    /// Skip tokens until we are at something that looks like the start of
+    /// a statement, so one mistake doesn't bury the errors that follow it
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while !self.check(TokenType::Eof) {
+            if self.previous().t_type == TokenType::Semicolon {
+                return;
+            }
+
+            match self.peek().t_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Switch
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    /// `class Name < Super { methods }`. The class is defined before its
+    /// body so the methods are bound to it while it sits on the stack
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let name_idx = self.current - 1;
+        let name_constant = self.identifier_constant(name_idx);
+        self.declare_variable();
+
+        self.emit(Op::Class(name_constant));
+        self.define_variable(name_constant);
+        self.classes.push(ClassState { has_superclass: false });
+
+        if self.matches(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if self.previous().lexeme == self.tokens[name_idx].lexeme {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // `super` is a local in a scope around the methods, so each of
+            // them captures the superclass as an upvalue
+            self.begin_scope();
+            self.add_local(Compiler::synthetic_token(TokenType::Identifier, "super"));
+            self.mark_initialized();
+
+            self.named_variable(name_idx, false);
+            self.emit(Op::Inherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        self.named_variable(name_idx, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit(Op::Pop);
+
+        let class = self.classes.pop().expect("class state was pushed");
+        if class.has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name_constant = self.identifier_constant(self.current - 1);
+
+        self.function(FunctionType::Method);
+        self.emit(Op::Method(name_constant));
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so it is usable before its body
+        self.mark_initialized();
+
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.matches(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit(Op::Nil);
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    /// Compiles the parameter list and body of a function, the name has
+    /// already been consumed. Leaves the function on the stack
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.previous().lexeme.clone();
+        self.begin_function(function_type, name);
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        self.parameters();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        self.end_closure();
+    }
+
+    /// `lambda (params) -> expr` or `lambda (params) { stmts }`, an
+    /// anonymous function left on the stack like any other value
+    fn lambda(&mut self, _can_assign: bool) {
+        if !self.require_extension(self.extensions.lambdas, "lambdas") {
+            return;
+        }
+
+        self.begin_function(FunctionType::Function, String::new());
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'lambda'.");
+        self.parameters();
+
+        if self.matches(TokenType::Arrow) {
+            // the expression body is its return value
+            self.expression();
+            self.emit(Op::Return);
+        } else {
+            self.consume(TokenType::LeftBrace, "Expect '->' or '{' after lambda parameters.");
+            self.block();
+        }
+
+        self.end_closure();
+    }
+
+    fn begin_function(&mut self, function_type: FunctionType, name: String) {
+        self.states.push(FunctionState::new(function_type, name));
+        self.begin_scope();
+    }
+
+    /// Parameters up to and including the closing `)`, they become the
+    /// first locals of the function
+    fn parameters(&mut self) {
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.state().function.arity == u8::MAX {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                } else {
+                    self.state_mut().function.arity += 1;
+                }
+                let param = self.parse_variable("Expect parameter name.");
+                self.define_variable(param);
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+    }
+
+    /// Finish the innermost function and emit the closure for it into
+    /// the enclosing one
+    fn end_closure(&mut self) {
+        let function = self.end_function();
+        let idx = self.chunk().add_constant(Constant::Function(Rc::new(function)));
+        self.emit(Op::Closure(idx));
+    }
+
+    fn statement(&mut self) {
+        if self.matches(TokenType::Return) {
+            self.return_statement();
+        } else if self.matches(TokenType::Print) {
+            self.print_statement();
+        } else if self.matches(TokenType::If) {
+            self.if_statement();
+        } else if self.matches(TokenType::Switch) {
+            self.switch_statement();
+        } else if self.matches(TokenType::While) {
+            self.while_statement();
+        } else if self.matches(TokenType::Del) {
+            self.del_statement();
+        } else if self.matches(TokenType::Break) {
+            self.break_statement();
+        } else if self.matches(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.matches(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit(Op::Print);
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+
+        if let Some(truthy) = self.constant_condition() {
+            // only the branch that can run gets compiled
+            let then_start = self.chunk().code.len();
+            self.statement();
+            if !truthy {
+                self.discard_code(then_start);
+            }
+
+            if self.matches(TokenType::Else) {
+                let else_start = self.chunk().code.len();
+                self.statement();
+                if truthy {
+                    self.discard_code(else_start);
+                }
+            }
+            return;
+        }
+
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(Op::JumpIfFalse(0));
+        self.emit(Op::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(Op::Jump(0));
+        self.patch_jump(then_jump);
+        self.emit(Op::Pop);
+
+        if self.matches(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk().code.len();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+
+        let condition = self.constant_condition();
+        let exit_jump = if condition.is_none() {
+            self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+            let jump = self.emit_jump(Op::JumpIfFalse(0));
+            self.emit(Op::Pop);
+            Some(jump)
+        } else {
+            None
+        };
+
+        let scope_depth = self.state().scope_depth;
+        self.state_mut().loops.push(LoopContext {
+            start: loop_start,
+            scope_depth,
+            break_jumps: Vec::new(),
+        });
+        self.statement();
+        self.emit(Op::Loop(loop_start));
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit(Op::Pop);
+        }
+
+        // a break skips the condition, so it lands after its Pop
+        let context = self.state_mut().loops.pop().expect("loop context was pushed");
+        for jump in context.break_jumps {
+            self.patch_jump(jump);
+        }
+
+        // a loop that never runs leaves nothing behind
+        if condition == Some(false) {
+            self.discard_code(loop_start);
+        }
+    }
+
+    /// A condition made of a single literal is decided here instead of at
+    /// runtime. Consumes it along with the closing ')' and returns its
+    /// truthiness, or leaves everything alone if it isn't constant.
+    fn constant_condition(&mut self) -> Option<bool> {
+        let closes = self
+            .tokens
+            .get(self.current + 1)
+            .is_some_and(|t| t.t_type == TokenType::RightParen);
+        if !closes {
+            return None;
+        }
+
+        let truthy = match self.peek().t_type {
+            TokenType::False | TokenType::Nil => false,
+            TokenType::True | TokenType::Number | TokenType::String => true,
+            _ => return None,
+        };
+
+        self.advance();
+        self.advance();
+        Some(truthy)
+    }
+
+    /// `del name;` forgets a global, locals go away with their scope
+    fn del_statement(&mut self) {
+        self.consume(TokenType::Identifier, "Expect variable name after 'del'.");
+        let name_idx = self.current - 1;
+
+        let name = self.tokens[name_idx].clone();
+        let (get_op, _) = self.variable_ops(&name);
+        if !matches!(get_op, Op::GetGlobal(_)) {
+            self.error("Can only delete global variables.");
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after variable name.");
+
+        let global = self.identifier_constant(name_idx);
+        self.emit(Op::DelGlobal(global));
+    }
+
+    fn break_statement(&mut self) {
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+
+        let Some(scope_depth) = self.state().loops.last().map(|l| l.scope_depth) else {
+            self.error("Can't use 'break' outside of a loop.");
+            return;
+        };
+
+        self.discard_locals(scope_depth);
+        let jump = self.emit_jump(Op::Jump(0));
+        if let Some(context) = self.state_mut().loops.last_mut() {
+            context.break_jumps.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        let Some((start, scope_depth)) = self
+            .state()
+            .loops
+            .last()
+            .map(|l| (l.start, l.scope_depth))
+        else {
+            self.error("Can't use 'continue' outside of a loop.");
+            return;
+        };
+
+        self.discard_locals(scope_depth);
+        self.emit(Op::Loop(start));
+    }
+
+    /// `switch (x) { case a: ... case b: ... default: ... }`
+    ///
+    /// The subject is evaluated once into a hidden local, then compared
+    /// against each case in turn. A matching case runs its statements and
+    /// jumps to the end, there is no fallthrough
+    fn switch_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after switch value.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
+
+        self.begin_scope();
+        // an empty name can't clash with, or be read by, user code
+        self.add_local(Compiler::synthetic_token(TokenType::Identifier, ""));
+        self.mark_initialized();
+        let subject = self.state().locals.len() - 1;
+
+        let mut end_jumps = Vec::new();
+        while self.matches(TokenType::Case) {
+            self.emit(Op::GetLocal(subject));
+            self.expression();
+            self.consume(TokenType::Colon, "Expect ':' after case value.");
+            self.emit(Op::Equal);
+
+            let next_case = self.emit_jump(Op::JumpIfFalse(0));
+            self.emit(Op::Pop);
+            self.case_body();
+            end_jumps.push(self.emit_jump(Op::Jump(0)));
+
+            self.patch_jump(next_case);
+            self.emit(Op::Pop);
+        }
+
+        if self.matches(TokenType::Default) {
+            self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+            self.case_body();
+
+            if self.check(TokenType::Case) {
+                self.error_at_current("Can't have a case after the default case.");
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    /// Statements up to the next `case`, `default` or the closing `}`
+    fn case_body(&mut self) {
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::Eof)
+        {
+            self.statement();
+        }
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.matches(TokenType::Semicolon) {
+            self.emit(Op::Nil);
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+
+            // A call whose result is returned straight away can reuse our
+            // frame, so tail recursion runs in constant frame space
+            if let Some(op) = self.chunk().code.last_mut() {
+                if let Op::Call(arg_count) = *op {
+                    *op = Op::TailCall(arg_count);
+                }
+            }
+        }
+        self.emit(Op::Return);
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit(Op::Pop);
+    }
+
+    // ------------------------------------------------------------------
+    // Expressions
+    // ------------------------------------------------------------------
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+
+        let prefix = match Compiler::get_rule(self.previous().t_type).prefix {
+            Some(prefix) => prefix,
+            None => {
+                self.error("Expect expression.");
+                return;
+            },
+        };
+        // ops emitted while this expression is parsed cover it from here
+        self.span_starts.push(self.previous().start_byte);
+
+        // Only an expression parsed at assignment precedence may be the
+        // target of an `=`, otherwise `a + b = 1` would assign to `b`
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign);
+
+        while precedence <= Compiler::get_rule(self.peek().t_type).precedence {
+            self.advance();
+            if let Some(infix) = Compiler::get_rule(self.previous().t_type).infix {
+                infix(self, can_assign);
+            }
+        }
+
+        self.span_starts.pop();
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    fn get_rule(t_type: TokenType) -> ParseRule {
+        use TokenType::*;
+
+        match t_type {
+            Question    => ParseRule::new(None, Some(Compiler::ternary), Precedence::Assignment),
+            Or      => ParseRule::new(None, Some(Compiler::or_), Precedence::Or),
+            And     => ParseRule::new(None, Some(Compiler::and_), Precedence::And),
+            Minus   => ParseRule::new(Some(Compiler::unary), Some(Compiler::binary), Precedence::Term),
+            Plus    => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+            Bang | Tilde    => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+            Pipe    => ParseRule::new(None, Some(Compiler::binary), Precedence::BitOr),
+            Caret   => ParseRule::new(None, Some(Compiler::binary), Precedence::BitXor),
+            Ampersand   => ParseRule::new(None, Some(Compiler::binary), Precedence::BitAnd),
+            ShiftLeft | ShiftRight  => ParseRule::new(None, Some(Compiler::binary), Precedence::Shift),
+            Slash | Star | Percent  => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+            EqualEqual | BangEqual  => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+            Greater | GreaterEqual | Less | LessEqual   => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+            LeftParen   => ParseRule::new(Some(Compiler::grouping), Some(Compiler::call), Precedence::Call),
+            Identifier  => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+            Lambda  => ParseRule::new(Some(Compiler::lambda), None, Precedence::None),
+            This    => ParseRule::new(Some(Compiler::this), None, Precedence::None),
+            Super   => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+            LeftBracket => ParseRule::new(Some(Compiler::list), Some(Compiler::index), Precedence::Call),
+            Number  => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+            String | Char   => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+            Nil | True | False  => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+            _   => ParseRule::new(None, None, Precedence::None),
+        }
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous().t_type;
+        if operator == TokenType::Tilde && !self.require_extension(self.extensions.bitwise, "bitwise") {
+            return;
+        }
+        self.parse_precedence(Precedence::Unary);
+
+        match operator {
+            TokenType::Minus    => self.emit(Op::Negate),
+            TokenType::Bang     => self.emit(Op::Not),
+            TokenType::Tilde    => self.emit(Op::BitNot),
+            _ => unreachable!("unary() called on a non unary operator"),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous().t_type;
+        let rule = Compiler::get_rule(operator);
+
+        let bitwise = rule.precedence >= Precedence::BitOr && rule.precedence <= Precedence::Shift;
+        if bitwise && !self.require_extension(self.extensions.bitwise, "bitwise") {
+            return;
+        }
+
+        self.parse_precedence(rule.precedence.next());
+
+        match operator {
+            TokenType::Plus     => self.emit(Op::Add),
+            TokenType::Minus    => self.emit(Op::Subtract),
+            TokenType::Star     => self.emit(Op::Multiply),
+            TokenType::Slash    => self.emit(Op::Divide),
+            TokenType::Percent  => self.emit(Op::Modulo),
+            TokenType::EqualEqual   => self.emit(Op::Equal),
+            TokenType::Greater  => self.emit(Op::Greater),
+            TokenType::Less     => self.emit(Op::Less),
+            // There are no opcodes for `!=`, `>=` and `<=`, each is the
+            // negation of the opposite test: `a >= b` is `!(a < b)`. This
+            // doesn't hold for NaN, where clox makes the same trade off
+            TokenType::BangEqual    => { self.emit(Op::Equal); self.emit(Op::Not) },
+            TokenType::GreaterEqual => { self.emit(Op::Less); self.emit(Op::Not) },
+            TokenType::LessEqual    => { self.emit(Op::Greater); self.emit(Op::Not) },
+            TokenType::Ampersand    => self.emit(Op::BitAnd),
+            TokenType::Pipe     => self.emit(Op::BitOr),
+            TokenType::Caret    => self.emit(Op::BitXor),
+            TokenType::ShiftLeft    => self.emit(Op::ShiftLeft),
+            TokenType::ShiftRight   => self.emit(Op::ShiftRight),
+            _ => unreachable!("binary() called on a non binary operator"),
+        }
+    }
+
+    /// `a and b`, when `a` is falsey it is the result and `b` never runs
+    fn and_(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(Op::JumpIfFalse(0));
+
+        self.emit(Op::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    /// `a or b`, when `a` is truthy it is the result and `b` never runs
+    fn or_(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(Op::JumpIfFalse(0));
+        let end_jump = self.emit_jump(Op::Jump(0));
+
+        self.patch_jump(else_jump);
+        self.emit(Op::Pop);
+        self.parse_precedence(Precedence::Or);
+
+        self.patch_jump(end_jump);
+    }
+
+    /// `cond ? a : b`, compiled like an if/else where each branch leaves
+    /// its value on the stack. Right associative, so `a ? b : c ? d : e`
+    /// nests in the else branch
+    fn ternary(&mut self, _can_assign: bool) {
+        if !self.require_extension(self.extensions.ternary, "ternary") {
+            return;
+        }
+
+        let else_jump = self.emit_jump(Op::JumpIfFalse(0));
+        self.emit(Op::Pop);
+        self.parse_precedence(Precedence::Assignment);
+
+        self.consume(TokenType::Colon, "Expect ':' after then branch of conditional expression.");
+        let end_jump = self.emit_jump(Op::Jump(0));
+
+        self.patch_jump(else_jump);
+        self.emit(Op::Pop);
+        self.parse_precedence(Precedence::Assignment);
+
+        self.patch_jump(end_jump);
+    }
+
+    /// `(expr)` only steers precedence, it emits nothing of its own
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+
+    /// `callee(args)`, the callee is already on the stack
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit(Op::Call(arg_count));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut count: usize = 0;
+
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                count += 1;
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+
+        count.min(u8::MAX as usize) as u8
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.current - 1, can_assign);
+    }
+
+    /// Emit a read of the variable named by the token at `token_idx`, or
+    /// a write if it is followed by `=` and assignment is allowed here
+    fn named_variable(&mut self, token_idx: usize, can_assign: bool) {
+        let name = self.tokens[token_idx].clone();
+        let (get_op, set_op) = self.variable_ops(&name);
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit(set_op);
+        } else {
+            self.emit(get_op);
+        }
+    }
+
+    /// Emit a read of one of the variables the compiler declares itself,
+    /// like `this` and `super`
+    fn synthetic_variable(&mut self, name: &str) {
+        let name = Compiler::synthetic_token(TokenType::Identifier, name);
+        let (get_op, _) = self.variable_ops(&name);
+        self.emit(get_op);
+    }
+
+    /// The get and set ops for `name`. Locals win over upvalues, which win
+    /// over globals
+    fn variable_ops(&mut self, name: &Token) -> (Op, Op) {
+        if let Some(slot) = self.resolve_local(name) {
+            (Op::GetLocal(slot), Op::SetLocal(slot))
+        } else if let Some(upvalue) = self.resolve_upvalue(self.states.len() - 1, name) {
+            (Op::GetUpvalue(upvalue), Op::SetUpvalue(upvalue))
+        } else {
+            let name_idx = self.chunk().add_constant_string(&name.lexeme);
+            (Op::GetGlobal(name_idx), Op::SetGlobal(name_idx))
+        }
+    }
+
+    /// `this` is the hidden slot 0 local of every method
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.synthetic_variable("this");
+    }
+
+    /// `super.name` looks `name` up on the superclass and binds it to `this`
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            },
+            Some(_) => (),
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name_constant = self.identifier_constant(self.current - 1);
+
+        self.synthetic_variable("this");
+        self.synthetic_variable("super");
+        self.emit(Op::GetSuper(name_constant));
+    }
+
+    /// `[a, b, c]`, the elements are pushed in order and then gathered
+    /// into one list. A trailing comma is allowed
+    fn list(&mut self, _can_assign: bool) {
+        if !self.require_extension(self.extensions.lists, "lists") {
+            return;
+        }
+
+        let mut count: usize = 0;
+
+        while !self.check(TokenType::RightBracket) && !self.check(TokenType::Eof) {
+            self.expression();
+            if count == u8::MAX as usize {
+                self.error("Can't have more than 255 elements in a list literal.");
+            }
+            count += 1;
+
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.");
+
+        self.emit(Op::BuildList(count.min(u8::MAX as usize) as u8));
+    }
+
+    /// `a[i]` and `a[i] = v`, the collection is already on the stack
+    fn index(&mut self, can_assign: bool) {
+        if !self.require_extension(self.extensions.lists, "lists") {
+            return;
+        }
+
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit(Op::IndexSet);
+        } else {
+            self.emit(Op::Index);
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let value = match self.previous().literal {
+            Some(Literal::Number(value)) => value,
+            Some(Literal::Int(value)) => value as f64,
+            _ => 0.0,
+        };
+        let idx = self.chunk().add_constant_number(value);
+        self.emit_constant(idx);
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        let value = match &self.previous().literal {
+            Some(Literal::Str(value)) => value.clone(),
+            // Lox has no character type, a char is a one character string
+            Some(Literal::Char(value)) => value.to_string(),
+            _ => std::string::String::new(),
+        };
+        let idx = self.chunk().add_constant_string(&value);
+        self.emit_constant(idx);
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous().t_type {
+            TokenType::Nil   => self.emit(Op::Nil),
+            TokenType::True  => self.emit(Op::True),
+            TokenType::False => self.emit(Op::False),
+            _ => unreachable!("literal() called on a non literal token"),
+        }
+    }
+
+    /// Report an error when the construct we are at belongs to a switched
+    /// off extension, returns whether compiling may go on
+    fn require_extension(&mut self, enabled: bool, name: &str) -> bool {
+        if !enabled {
+            self.error(&format!("The '{}' feature is not enabled.", name));
+        }
+        enabled
+    }
+
+    // ------------------------------------------------------------------
+    // Variables and scopes
+    // ------------------------------------------------------------------
+
+    /// Store the name of the token at `token_idx` in the constant pool.
+    /// Goes through `add_constant_string` so a name used many times is
+    /// only stored once
+    fn identifier_constant(&mut self, token_idx: usize) -> usize {
+        let name = self.tokens[token_idx].lexeme.clone();
+        self.chunk().add_constant_string(&name)
+    }
+
+    /// Consume a variable name and declare it. Returns the index of the
+    /// name constant for globals, locals don't need one
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.current - 1)
+    }
+
+    /// Locals are declared by adding them to the scope, globals are late
+    /// bound so there is nothing to do for them
+    fn declare_variable(&mut self) {
+        if self.state().scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous().clone();
+
+        // only the current scope counts, shadowing an outer one is fine
+        let state = self.state();
+        let already_declared = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == -1 || local.depth >= state.scope_depth)
+            .any(|local| local.name.lexeme == name.lexeme);
+        if already_declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        // depth stays -1 until the initializer has been compiled
+        self.state_mut().locals.push(Local {
+            name,
+            depth: -1,
+            is_captured: false,
+        });
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            // the value is already sitting in the local's stack slot
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit(Op::DefineGlobal(global));
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let depth = state.scope_depth;
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = depth;
+        }
+    }
+
+    /// Find the stack slot of a local in the function being compiled
+    fn resolve_local(&mut self, name: &Token) -> Option<usize> {
+        self.resolve_local_in(self.states.len() - 1, name)
+    }
+
+    /// Find the stack slot of a local in the function at `state_idx`.
+    /// Scanning from the top means the innermost shadowing variable wins
+    fn resolve_local_in(&mut self, state_idx: usize, name: &Token) -> Option<usize> {
+        let slot = self.states[state_idx]
+            .locals
+            .iter()
+            .rposition(|local| local.name.lexeme == name.lexeme)?;
+
+        if self.states[state_idx].locals[slot].depth == -1 {
+            self.error("Can't read local variable in its own initializer.");
+        }
+
+        Some(slot)
+    }
+
+    /// Look for `name` in the functions enclosing the one at `state_idx`,
+    /// threading an upvalue through every function in between
+    fn resolve_upvalue(&mut self, state_idx: usize, name: &Token) -> Option<usize> {
+        if state_idx == 0 {
+            // the script has nothing around it
+            return None;
+        }
+        let enclosing = state_idx - 1;
+
+        if let Some(slot) = self.resolve_local_in(enclosing, name) {
+            self.states[enclosing].locals[slot].is_captured = true;
+            return Some(self.add_upvalue(state_idx, slot, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(state_idx, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, state_idx: usize, index: usize, is_local: bool) -> usize {
+        let upvalue = Upvalue {
+            is_local,
+            index,
+        };
+        let upvalues = &mut self.states[state_idx].function.upvalues;
+
+        // closing over the same variable twice shares the upvalue
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
+        }
+
+        upvalues.push(upvalue);
+        upvalues.len() - 1
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        loop {
+            let state = self.state();
+            match state.locals.last() {
+                Some(local) if local.depth > state.scope_depth => {
+                    // captured locals move to the heap instead of dying
+                    let op = if local.is_captured { Op::CloseUpvalue } else { Op::Pop };
+                    self.state_mut().locals.pop();
+                    self.emit(op);
+                },
+                _ => break,
+            }
+        }
+    }
+
+    /// Emit the pops for the locals deeper than `depth` without forgetting
+    /// them, for jumps that leave their scope early
+    fn discard_locals(&mut self, depth: i64) {
+        let ops: Vec<Op> = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| if local.is_captured { Op::CloseUpvalue } else { Op::Pop })
+            .collect();
+
+        for op in ops {
+            self.emit(op);
+        }
+    }
+
+    /// Finish the innermost function and hand it back. Every function gets
+    /// a trailing `return nil;`, so falling off the end still tears the
+    /// frame down, even after an explicit return
+    fn end_function(&mut self) -> Function {
+        self.emit(Op::Nil);
+        self.emit(Op::Return);
+
+        let state = self.states.pop().expect("no function is being compiled");
+        state.function
+    }
+
+    // ------------------------------------------------------------------
+    // Emitting
+    // ------------------------------------------------------------------
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().expect("no function is being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("no function is being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn emit(&mut self, op: Op) {
+        let previous = self.previous();
+        let line = LineNo::new(previous.line);
+        let col = previous.col;
+
+        // outside of an expression an op only covers the token before it
+        let start = self.span_starts.last().copied().unwrap_or(previous.start_byte);
+        let span = (start, previous.end_byte);
+
+        let chunk = self.chunk();
+        chunk.write(op, line);
+        chunk.spans.push(span);
+        chunk.columns.push(col);
+    }
+
+    /// Throw away everything emitted from `start` on, along with any
+    /// `break` that was waiting to be patched in there
+    fn discard_code(&mut self, start: usize) {
+        self.chunk().truncate(start);
+
+        for context in &mut self.state_mut().loops {
+            context.break_jumps.retain(|&jump| jump < start);
+        }
+    }
+
+    /// Load constant `idx`, in the long form once it outgrows a byte
+    fn emit_constant(&mut self, idx: usize) {
+        self.emit(Op::load_constant(idx));
+    }
+
+    /// Emit a jump whose target isn't known yet, returns where it is so
+    /// `patch_jump` can fill it in later
+    fn emit_jump(&mut self, op: Op) -> usize {
+        self.emit(op);
+        self.chunk().code.len() - 1
+    }
+
+    /// Point the jump at `jump_idx` to the next op that will be emitted
+    fn patch_jump(&mut self, jump_idx: usize) {
+        let target = self.chunk().code.len();
+
+        match &mut self.chunk().code[jump_idx] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            other => unreachable!("patch_jump() called on {:?}", other),
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Token handling
+    // ------------------------------------------------------------------
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current.saturating_sub(1)]
+    }
+
+    fn advance(&mut self) {
+        // never walk past the Eof
+        if self.peek().t_type != TokenType::Eof {
+            self.current += 1;
+        }
+    }
+
+    fn check(&self, t_type: TokenType) -> bool {
+        self.peek().t_type == t_type
+    }
+
+    fn matches(&mut self, t_type: TokenType) -> bool {
+        if !self.check(t_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn consume(&mut self, t_type: TokenType, message: &str) {
+        if self.check(t_type) {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    /// Report an error at the token we just consumed
+    fn error(&mut self, message: &str) {
+        self.error_at(self.current.saturating_sub(1), message);
+    }
+
+    /// Report an error at the token we are about to consume
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(self.current, message);
+    }
+
+    fn error_at(&mut self, token_idx: usize, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+
+        let token = &self.tokens[token_idx];
+        let location = match token.t_type {
+            TokenType::Eof  => " at end".to_string(),
+            _   => format!(" at '{}'", token.lexeme),
+        };
+
+        self.errors.push(CompileError {
+            message: message.to_string(),
+            line: token.line,
+            col: token.start_col,
+            location,
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn compile_source(source: &str) -> Result<Function, Vec<CompileError>> {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(source.to_string());
+        compile(scanner.tokens().to_vec())
+    }
+
+    /// The `nth` function stored in the constants of `chunk`
+    fn function_constant(chunk: &Chunk, nth: usize) -> Rc<Function> {
+        chunk
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                Constant::Function(function) => Some(function.clone()),
+                _ => None,
+            })
+            .nth(nth)
+            .expect("no such function constant")
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<Op> {
+        chunk.code.clone()
+    }
+
+    #[test]
+    fn expressions_compile_by_precedence() {
+        let script = compile_source("-1 + 2 * !3;").unwrap();
+
+        let code = ops(&script.chunk);
+        assert!(matches!(code[0], Op::Constant(_)));
+        assert!(matches!(code[1], Op::Negate));
+        assert!(matches!(code[2], Op::Constant(_)));
+        assert!(matches!(code[3], Op::Constant(_)));
+        assert!(matches!(code[4], Op::Not));
+        assert!(matches!(code[5], Op::Multiply));
+        assert!(matches!(code[6], Op::Add));
+        assert!(matches!(code[7], Op::Pop));
+
+        let listing = crate::bytecode_interpreter::dis_chunk(&script.chunk, "script");
+        assert!(listing.contains("OP_CONSTANT 2 (idx=1)"));
+        assert!(listing.contains("OP_MULTIPLY"));
+    }
+
+    #[test]
+    fn grouping_overrides_precedence() {
+        let grouped = ops(&compile_source("(1 + 2) * 3;").unwrap().chunk);
+        let plain = ops(&compile_source("1 + 2 * 3;").unwrap().chunk);
+
+        assert_eq!(grouped, vec![
+            Op::Constant(0), Op::Constant(1), Op::Add,
+            Op::Constant(2), Op::Multiply, Op::Pop, Op::Nil, Op::Return,
+        ]);
+        assert_eq!(plain, vec![
+            Op::Constant(0), Op::Constant(1), Op::Constant(2),
+            Op::Multiply, Op::Add, Op::Pop, Op::Nil, Op::Return,
+        ]);
+    }
+
+    #[test]
+    fn unclosed_grouping_is_an_error() {
+        let errors = compile_source("(1 + 2;").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("Expect ')' after expression."));
+    }
+
+    #[test]
+    fn negated_comparisons_desugar_to_two_ops() {
+        for (source, test) in [("1 != 2;", Op::Equal), ("1 >= 2;", Op::Less), ("1 <= 2;", Op::Greater)] {
+            let code = ops(&compile_source(source).unwrap().chunk);
+
+            assert_eq!(&code[2..4], &[test, Op::Not], "{}", source);
+        }
+
+        let code = ops(&compile_source("1 == 2;").unwrap().chunk);
+        assert_eq!(code[2], Op::Equal);
+        assert_eq!(code[3], Op::Pop);
+    }
+
+    #[test]
+    fn equality_binds_looser_than_comparison() {
+        let code = ops(&compile_source("1 < 2 == true;").unwrap().chunk);
+
+        assert_eq!(&code[..5], &[
+            Op::Constant(0), Op::Constant(1), Op::Less, Op::True, Op::Equal,
+        ]);
+    }
+
+    #[test]
+    fn constants_past_a_byte_use_the_long_form() {
+        let source: std::string::String = (0..300).map(|n| format!("{};", n)).collect();
+        let script = compile_source(&source).unwrap();
+
+        let loads: Vec<Op> = ops(&script.chunk)
+            .into_iter()
+            .filter(|op| matches!(op, Op::Constant(_) | Op::ConstantLong(_)))
+            .collect();
+        assert_eq!(loads.len(), 300);
+        assert_eq!(loads[255], Op::Constant(255));
+        assert_eq!(loads[256], Op::ConstantLong(256));
+        assert_eq!(loads[299], Op::ConstantLong(299));
+
+        let listing = crate::bytecode_interpreter::dis_chunk(&script.chunk, "script");
+        assert!(listing.contains("OP_CONSTANT 255 (idx=255)"));
+        assert!(listing.contains("OP_CONSTANT_LONG 256 (idx=256)"));
+    }
+
+    #[test]
+    fn function_declaration_stores_its_arity_and_name() {
+        let script = compile_source("fun add(a, b) { return a + b; }").unwrap();
+
+        let function = function_constant(&script.chunk, 0);
+        assert_eq!(function.arity, 2);
+        assert_eq!(function.name, "add");
+        assert!(matches!(ops(&script.chunk)[..2], [Op::Closure(_), Op::DefineGlobal(_)]));
+        // the parameters are the locals after the function's own slot
+        assert!(matches!(ops(&function.chunk)[..3], [Op::GetLocal(1), Op::GetLocal(2), Op::Add]));
+    }
+
+    #[test]
+    fn too_many_parameters_is_an_error() {
+        let params: Vec<std::string::String> = (0..256).map(|n| format!("p{}", n)).collect();
+        let source = format!("fun f({}) {{}}", params.join(", "));
+        let errors = compile_source(&source).unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("Can't have more than 255 parameters."));
+    }
+
+    #[test]
+    fn and_and_or_compile_to_jumps() {
+        let code = ops(&compile_source("a and b;").unwrap().chunk);
+        assert_eq!(code[..5], [Op::GetGlobal(0), Op::JumpIfFalse(4), Op::Pop, Op::GetGlobal(1), Op::Pop]);
+
+        let code = ops(&compile_source("a or b;").unwrap().chunk);
+        assert_eq!(code[..6], [
+            Op::GetGlobal(0), Op::JumpIfFalse(3), Op::Jump(5), Op::Pop, Op::GetGlobal(1), Op::Pop,
+        ]);
+    }
+
+    #[test]
+    fn return_inside_function_emits_value_then_return() {
+        let script = compile_source("fun five() { return 5; }").unwrap();
+
+        let function = function_constant(&script.chunk, 0);
+
+        let code = ops(&function.chunk);
+        assert!(matches!(code[0], Op::Constant(_)));
+        assert!(matches!(code[1], Op::Return));
+    }
+
+    #[test]
+    fn return_at_top_level_is_an_error() {
+        let errors = compile_source("return 5;").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("Can't return from top-level code."));
+    }
+
+    #[test]
+    fn synchronize_reports_an_error_per_statement() {
+        let errors = compile_source("1 2;\nfun {}").unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].line, 1);
+        assert!(errors[0].message.contains("Expect ';' after expression."));
+        assert_eq!(errors[1].line, 2);
+        assert!(errors[1].message.contains("Expect function name."));
+    }
+
+    #[test]
+    fn assignment_to_a_variable_compiles() {
+        let script = compile_source("a = 1;").unwrap();
+
+        let code = ops(&script.chunk);
+        assert!(matches!(code[0], Op::Constant(_)));
+        assert!(matches!(code[1], Op::SetGlobal(_)));
+    }
+
+    #[test]
+    fn assignment_to_an_expression_is_an_error() {
+        for source in ["a + b = 1;", "1 = 2;"] {
+            let errors = compile_source(source).unwrap_err();
+
+            assert_eq!(errors.len(), 1, "{}", source);
+            assert!(errors[0].message.contains("Invalid assignment target."), "{}", source);
+        }
+    }
+
+    #[test]
+    fn closure_captures_enclosing_local() {
+        let source = "
+            fun makeCounter(count) {
+                fun counter() {
+                    count = count + 1;
+                    return count;
+                }
+                return counter;
+            }
+        ";
+        let script = compile_source(source).unwrap();
+        let make_counter = function_constant(&script.chunk, 0);
+        let counter = function_constant(&make_counter.chunk, 0);
+
+        // `count` is parameter slot 1 of makeCounter
+        assert_eq!(counter.upvalues, vec![Upvalue { is_local: true, index: 1 }]);
+
+        let code = ops(&counter.chunk);
+        assert!(matches!(code[0], Op::GetUpvalue(0)));
+        assert!(code.iter().any(|op| matches!(op, Op::SetUpvalue(0))));
+
+        let outer = ops(&make_counter.chunk);
+        assert!(matches!(outer[0], Op::Closure(_)));
+        assert!(matches!(outer[1], Op::GetLocal(2)));
+    }
+
+    #[test]
+    fn block_locals_resolve_to_their_slots() {
+        let script = compile_source("{ var a = 1; { var b = 2; b; a; } }").unwrap();
+
+        // slot 0 is reserved for the script itself
+        let reads: Vec<Op> = ops(&script.chunk)
+            .into_iter()
+            .filter(|op| matches!(op, Op::GetLocal(_)))
+            .collect();
+        assert!(matches!(reads[..], [Op::GetLocal(2), Op::GetLocal(1)]));
+    }
+
+    #[test]
+    fn local_in_its_own_initializer_is_an_error() {
+        let errors = compile_source("{ var a = a; }").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("Can't read local variable in its own initializer."));
+    }
+
+    #[test]
+    fn repeated_names_share_one_constant() {
+        let script = compile_source("var x = \"x\"; x = 2; x;").unwrap();
+
+        let names = script
+            .chunk
+            .constants
+            .iter()
+            .filter(|constant| matches!(constant, Constant::String(value) if value == "x"))
+            .count();
+        assert_eq!(names, 1);
+    }
+
+    #[test]
+    fn lambda_compiles_to_an_anonymous_function() {
+        let script = compile_source("var f = lambda (x) -> x + 1;").unwrap();
+        let lambda = function_constant(&script.chunk, 0);
+
+        assert_eq!(lambda.name, "");
+        assert_eq!(lambda.to_string(), "<lambda>");
+        assert_eq!(lambda.arity, 1);
+        assert!(matches!(ops(&script.chunk)[0], Op::Closure(_)));
+
+        let code = ops(&lambda.chunk);
+        assert!(matches!(code[..4], [Op::GetLocal(1), Op::Constant(_), Op::Add, Op::Return]));
+    }
+
+    #[test]
+    fn lambda_with_block_body() {
+        let script = compile_source("var f = lambda (a, b) { return a; };").unwrap();
+
+        assert_eq!(function_constant(&script.chunk, 0).arity, 2);
+    }
+
+    #[test]
+    fn list_literal_builds_from_its_elements() {
+        let script = compile_source("[1, 2];").unwrap();
+
+        let code = ops(&script.chunk);
+        assert!(matches!(code[..3], [Op::Constant(0), Op::Constant(1), Op::BuildList(2)]));
+    }
+
+    #[test]
+    fn empty_and_trailing_comma_lists() {
+        let script = compile_source("[];").unwrap();
+        assert!(matches!(ops(&script.chunk)[0], Op::BuildList(0)));
+
+        let script = compile_source("[1, 2,];").unwrap();
+        assert!(matches!(ops(&script.chunk)[2], Op::BuildList(2)));
+    }
+
+    #[test]
+    fn index_and_index_assignment() {
+        let script = compile_source("a[0];").unwrap();
+        let code = ops(&script.chunk);
+        assert!(matches!(code[..3], [Op::GetGlobal(_), Op::Constant(_), Op::Index]));
+
+        let script = compile_source("a[0] = 5;").unwrap();
+        let code = ops(&script.chunk);
+        assert!(matches!(
+            code[..4],
+            [Op::GetGlobal(_), Op::Constant(_), Op::Constant(_), Op::IndexSet]
+        ));
+    }
+
+    #[test]
+    fn ternary_compiles_to_a_jump_pair() {
+        let script = compile_source("x ? 1 : 2;").unwrap();
+
+        let code = ops(&script.chunk);
+        assert!(matches!(
+            code[..8],
+            [
+                Op::GetGlobal(_),
+                Op::JumpIfFalse(5),
+                Op::Pop,
+                Op::Constant(_),
+                Op::Jump(7),
+                Op::Pop,
+                Op::Constant(_),
+                Op::Pop,
+            ]
+        ));
+    }
+
+    #[test]
+    fn ternary_without_colon_is_an_error() {
+        let errors = compile_source("x ? 1;").unwrap_err();
+
+        assert!(errors[0].message.contains("Expect ':'"));
+    }
+
+    #[test]
+    fn lists_follow_the_extension_flag() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("[1, 2];".to_string());
+        let tokens = scanner.tokens().to_vec();
+
+        let without_lists = Extensions {
+            lists: false,
+            ..Default::default()
+        };
+        let errors = compile_with(tokens.clone(), without_lists).unwrap_err();
+        assert!(errors[0].message.contains("The 'lists' feature is not enabled."));
+
+        assert!(compile_with(tokens, Extensions::default()).is_ok());
+    }
+
+    #[test]
+    fn ops_carry_the_span_of_their_expression() {
+        let script = compile_source("1 + 2;").unwrap();
+        let chunk = &script.chunk;
+
+        assert_eq!(chunk.spans.len(), chunk.code.len());
+        assert!(matches!(chunk.code[2], Op::Add));
+        assert_eq!(chunk.spans[0], (0, 1));
+        assert_eq!(chunk.spans[1], (4, 5));
+        assert_eq!(chunk.spans[2], (0, 5));
+    }
+
+    #[test]
+    fn returned_call_becomes_a_tail_call() {
+        let script = compile_source("fun f(n) { return f(n); }").unwrap();
+        let code = ops(&function_constant(&script.chunk, 0).chunk);
+        assert!(matches!(code[..4], [Op::GetGlobal(_), Op::GetLocal(1), Op::TailCall(1), Op::Return]));
+
+        // the call's result is still needed here
+        let script = compile_source("fun f(n) { return 1 + f(n); }").unwrap();
+        let code = ops(&function_constant(&script.chunk, 0).chunk);
+        assert!(code.iter().any(|op| matches!(op, Op::Call(1))));
+        assert!(!code.iter().any(|op| matches!(op, Op::TailCall(_))));
+    }
+
+    #[test]
+    fn too_many_locals_is_an_error() {
+        let declare = |count: usize| -> String {
+            let vars: Vec<String> = (0..count)
+                .map(|i| format!("var {};", "v".repeat(i + 1)))
+                .collect();
+            format!("{{ {} }}", vars.join(" "))
+        };
+
+        // slot 0 is taken, which leaves room for 255
+        assert!(compile_source(&declare(255)).is_ok());
+
+        // one error for each declaration that doesn't fit
+        let errors = compile_source(&declare(257)).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| e.message.contains("Too many local variables in function.")));
+    }
+
+    #[test]
+    fn redeclaring_a_local_in_the_same_scope_is_an_error() {
+        let errors = compile_source("{ var a = 1; var a = 2; }").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("Already a variable with this name in this scope."));
+    }
+
+    #[test]
+    fn shadowing_in_an_inner_scope_is_allowed() {
+        assert!(compile_source("{ var a = 1; { var a = 2; } }").is_ok());
+        assert!(compile_source("var a = 1; var a = 2;").is_ok());
+    }
+
+    #[test]
+    fn modulo_is_a_factor_operator() {
+        let script = compile_source("7 % 3 + 1;").unwrap();
+
+        let code = ops(&script.chunk);
+        assert!(matches!(
+            code[..5],
+            [Op::Constant(_), Op::Constant(_), Op::Modulo, Op::Constant(_), Op::Add]
+        ));
+    }
+
+    #[test]
+    fn bitwise_operators_bind_tighter_than_comparisons() {
+        let script = compile_source("1 | 2 < 3 << 1;").unwrap();
+
+        // (1 | 2) < (3 << 1)
+        let code = ops(&script.chunk);
+        assert!(matches!(
+            code[..7],
+            [
+                Op::Constant(_),
+                Op::Constant(_),
+                Op::BitOr,
+                Op::Constant(_),
+                Op::Constant(_),
+                Op::ShiftLeft,
+                Op::Less,
+            ]
+        ));
+    }
+
+    #[test]
+    fn switch_compiles_to_a_chain_of_tests() {
+        let source = "switch (x) { case 1: a; case 2: b; case 3: c; default: d; }";
+        let script = compile_source(source).unwrap();
+        let code = ops(&script.chunk);
+
+        let tests = code.iter().filter(|op| matches!(op, Op::JumpIfFalse(_))).count();
+        assert_eq!(tests, 3);
+
+        // every case leaves through the same exit, which pops the subject
+        let exits: Vec<usize> = code
+            .iter()
+            .filter_map(|op| match op {
+                Op::Jump(target) => Some(*target),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(exits.len(), 3);
+        assert!(exits.iter().all(|target| *target == exits[0]));
+        assert!(matches!(code[exits[0]], Op::Pop));
+
+        // a failed test skips to the next one
+        let Op::JumpIfFalse(second) = code[4] else {
+            panic!("expected the first test at offset 4");
+        };
+        assert!(matches!(code[second..second + 2], [Op::Pop, Op::GetLocal(1)]));
+    }
+
+    #[test]
+    fn break_jumps_past_the_loop_and_continue_loops_back() {
+        let script = compile_source("while (x) { break; }").unwrap();
+        let code = ops(&script.chunk);
+        // 0 GetGlobal, 1 JumpIfFalse, 2 Pop, 3 Jump, 4 Loop, 5 Pop
+        assert!(matches!(code[..6], [
+            Op::GetGlobal(_),
+            Op::JumpIfFalse(5),
+            Op::Pop,
+            Op::Jump(6),
+            Op::Loop(0),
+            Op::Pop,
+        ]));
+
+        let script = compile_source("while (x) { continue; }").unwrap();
+        let code = ops(&script.chunk);
+        assert!(matches!(code[3], Op::Loop(0)));
+    }
+
+    #[test]
+    fn break_outside_a_loop_is_an_error() {
+        let errors = compile_source("break;").unwrap_err();
+        assert!(errors[0].message.contains("Can't use 'break' outside of a loop."));
+
+        let errors = compile_source("fun f() { continue; }").unwrap_err();
+        assert!(errors[0].message.contains("Can't use 'continue' outside of a loop."));
+    }
+
+    #[test]
+    fn constant_if_conditions_drop_the_dead_branch() {
+        let script = compile_source("if (false) print 1;").unwrap();
+        assert!(!ops(&script.chunk).iter().any(|op| matches!(op, Op::Print)));
+
+        let script = compile_source("if (true) print 1;").unwrap();
+        let code = ops(&script.chunk);
+        assert!(matches!(code[..2], [Op::Constant(_), Op::Print]));
+        assert!(!code.iter().any(|op| matches!(op, Op::Jump(_) | Op::JumpIfFalse(_))));
+
+        let script = compile_source("if (nil) print 1; else print 2;").unwrap();
+        let code = ops(&script.chunk);
+        assert_eq!(code.iter().filter(|op| matches!(op, Op::Print)).count(), 1);
+    }
+
+    #[test]
+    fn constant_while_conditions_skip_the_test() {
+        let script = compile_source("while (false) print 1;").unwrap();
+        assert!(!ops(&script.chunk).iter().any(|op| matches!(op, Op::Print)));
+
+        let script = compile_source("while (true) { print 1; break; }").unwrap();
+        let code = ops(&script.chunk);
+        assert!(matches!(code[..4], [Op::Constant(_), Op::Print, Op::Jump(4), Op::Loop(0)]));
+    }
+
+    #[test]
+    fn breaks_in_an_elided_branch_are_dropped() {
+        let script = compile_source("while (x) { if (false) break; }").unwrap();
+        let code = ops(&script.chunk);
+        assert!(!code.iter().any(|op| matches!(op, Op::Jump(_))));
+    }
+
+    #[test]
+    fn class_declaration_emits_class_and_methods() {
+        let script = compile_source("class Foo {}").unwrap();
+        let code = ops(&script.chunk);
+        let Op::Class(name_idx) = code[0] else {
+            panic!("expected OP_CLASS, got {:?}", code[0]);
+        };
+        assert!(matches!(&script.chunk.constants[name_idx], Constant::String(name) if name == "Foo"));
+        assert!(matches!(code[1], Op::DefineGlobal(idx) if idx == name_idx));
+
+        let script = compile_source("class Foo { bar() {} }").unwrap();
+        let code = ops(&script.chunk);
+        assert!(matches!(code[..5], [
+            Op::Class(_),
+            Op::DefineGlobal(_),
+            Op::GetGlobal(_),
+            Op::Closure(_),
+            Op::Method(_),
+        ]));
+        assert_eq!(function_constant(&script.chunk, 0).name, "bar");
+    }
+
+    #[test]
+    fn this_resolves_to_slot_zero_of_the_method() {
+        let script = compile_source("class Foo { bar() { return this; } }").unwrap();
+        let method = function_constant(&script.chunk, 0);
+        assert!(matches!(ops(&method.chunk)[0], Op::GetLocal(0)));
+
+        let errors = compile_source("print this;").unwrap_err();
+        assert!(errors[0].message.contains("Can't use 'this' outside of a class."));
+
+        let errors = compile_source("fun f() { return this; }").unwrap_err();
+        assert!(errors[0].message.contains("Can't use 'this' outside of a class."));
+    }
+
+    #[test]
+    fn super_reads_this_and_the_captured_superclass() {
+        let script = compile_source("class A {} class B < A { f() { return super.f; } }").unwrap();
+        let method = function_constant(&script.chunk, 0);
+        assert!(matches!(ops(&method.chunk)[..3], [
+            Op::GetLocal(0),
+            Op::GetUpvalue(0),
+            Op::GetSuper(_),
+        ]));
+
+        let errors = compile_source("class A { f() { return super.f; } }").unwrap_err();
+        assert!(errors[0].message.contains("Can't use 'super' in a class with no superclass."));
+
+        let errors = compile_source("class A < A {}").unwrap_err();
+        assert!(errors[0].message.contains("A class can't inherit from itself."));
+    }
+
+    #[test]
+    fn functions_without_return_end_with_an_implicit_one() {
+        let script = compile_source("fun f() { 1; }").unwrap();
+        let function = function_constant(&script.chunk, 0);
+        let code = ops(&function.chunk);
+        assert!(matches!(code[code.len() - 2..], [Op::Nil, Op::Return]));
+
+        let code = ops(&script.chunk);
+        assert!(matches!(code[code.len() - 2..], [Op::Nil, Op::Return]));
+    }
+
+    #[test]
+    fn else_if_chains_patch_every_jump_to_the_end() {
+        let script = compile_source("if (a) print 1; else if (b) print 2; else print 3;").unwrap();
+        let code = ops(&script.chunk);
+
+        let jumps: Vec<usize> = code
+            .iter()
+            .filter_map(|op| match op {
+                Op::Jump(target) => Some(*target),
+                _ => None,
+            })
+            .collect();
+        let conditional = code.iter().filter(|op| matches!(op, Op::JumpIfFalse(_))).count();
+        assert_eq!(jumps.len(), 2);
+        assert_eq!(conditional, 2);
+
+        // both branches that ran skip straight past the final else
+        let end = code.len() - 2;
+        assert_eq!(jumps, vec![end, end]);
+    }
+
+    #[test]
+    fn empty_input_compiles_to_the_implicit_return() {
+        let script = compile_source("").unwrap();
+        assert!(matches!(ops(&script.chunk)[..], [Op::Nil, Op::Return]));
+        assert!(script.chunk.constants.is_empty());
+    }
+
+    #[test]
+    fn del_only_accepts_globals() {
+        let script = compile_source("del a;").unwrap();
+        assert!(matches!(ops(&script.chunk)[0], Op::DelGlobal(0)));
+
+        let errors = compile_source("{ var a = 1; del a; }").unwrap_err();
+        assert!(errors[0].message.contains("Can only delete global variables."));
+    }
+
+    #[test]
+    fn compile_errors_carry_their_position() {
+        let errors = compile_source("var a = 1\nvar b = 2;\n\nreturn b;").unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Expect ';' after variable declaration.");
+        assert_eq!((errors[0].line, errors[0].col), (2, 1));
+        assert_eq!(errors[1].message, "Can't return from top-level code.");
+        assert_eq!((errors[1].line, errors[1].col), (4, 1));
+        assert_eq!(
+            errors[1].to_string(),
+            "[line 4] Error at 'return': Can't return from top-level code."
+        );
+
+        // the column is where the offending token starts
+        let errors = compile_source("print 1 + class;").unwrap_err();
+        assert_eq!((errors[0].line, errors[0].col), (1, 11));
+    }
+}
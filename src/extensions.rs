@@ -1,3 +1,62 @@
 /*
-    extension.rs: IDK what this file does
-*/
\ No newline at end of file
+    extensions.rs: Switches for the syntax we support on top of plain Lox.
+*/
+
+use crate::scanner::TokenType;
+
+
+/// Non-standard syntax a host can turn on or off at runtime.
+/// Everything is enabled by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    // `[1, 2]` literals and `a[i]` indexing
+    pub lists: bool,
+
+    // `lambda (x) -> x + 1`
+    pub lambdas: bool,
+
+    // `cond ? a : b`
+    pub ternary: bool,
+
+    // `& | ^ ~ << >>` on the integer value of numbers
+    pub bitwise: bool,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions {
+            lists: true,
+            lambdas: true,
+            ternary: true,
+            bitwise: true,
+        }
+    }
+}
+
+impl Extensions {
+    /// Plain Lox, nothing extra
+    pub fn none() -> Self {
+        Extensions {
+            lists: false,
+            lambdas: false,
+            ternary: false,
+            bitwise: false,
+        }
+    }
+
+    /// Name of the extension `t_type` belongs to, when that extension is
+    /// switched off
+    pub fn disabled_by(&self, t_type: TokenType) -> Option<&'static str> {
+        use TokenType::*;
+
+        match t_type {
+            LeftBracket | RightBracket if !self.lists   => Some("lists"),
+            Lambda | Arrow if !self.lambdas => Some("lambdas"),
+            // `:` is also used by `case`, so only `?` belongs to the ternary
+            Question if !self.ternary   => Some("ternary"),
+            Ampersand | Pipe | Caret | Tilde | ShiftLeft | ShiftRight
+                if !self.bitwise    => Some("bitwise"),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+/*
+    lib.rs: The public face of the crate, the binary is built on top of it.
+*/
+
+pub mod bytecode;
+pub mod bytecode_interpreter;
+pub mod compiler;
+pub mod extensions;
+pub mod scanner;
+pub mod serialize;
+pub mod vm;
+
+pub use bytecode::{
+    Chunk,
+    ChunkStats,
+    Constant,
+    ConstantStats,
+    Function,
+    Op,
+};
+pub use bytecode_interpreter::{
+    diff_chunks,
+    dis_chunk,
+    dis_chunk_json,
+    dis_chunk_with_stats,
+    dis_code,
+    dis_op,
+};
+pub use compiler::CompileError;
+pub use extensions::Extensions;
+pub use scanner::{
+    dump_tokens,
+    Scanner,
+    Token,
+    TokenType,
+};
+pub use serialize::DecodeError;
+pub use vm::{
+    format_value,
+    RuntimeError,
+    Value,
+    VM,
+};
+
+
+/// Anything that can go wrong between source text and a finished run
+#[derive(Debug)]
+pub enum Error {
+    Scan(Vec<scanner::Error>),
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Scan(errors) => {
+                let lines: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            },
+            Error::Compile(errors)  => {
+                let lines: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            },
+            Error::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Scan and compile `source` into its top level function
+pub fn compile(source: &str) -> Result<Function, Error> {
+    let tokens = Scanner::new().scan(source.to_string()).map_err(Error::Scan)?;
+    compiler::compile(tokens).map_err(Error::Compile)
+}
+
+/// Like `compile`, with the constant folding passes run over the result.
+/// See `Chunk::peephole` and `Chunk::fold_constants`
+pub fn compile_optimized(source: &str) -> Result<Function, Error> {
+    let mut script = compile(source)?;
+    script.chunk.peephole();
+    script.chunk.fold_constants();
+    Ok(script)
+}
+
+/// Scan, compile and run `source` on a fresh `VM`
+pub fn interpret(source: &str) -> Result<(), Error> {
+    let script = compile(source)?;
+
+    VM::new().interpret_with_source(script, source).map_err(Error::Runtime)?;
+    Ok(())
+}
+
+/// The disassembly of the top level chunk `source` compiles to
+pub fn dump(source: &str) -> Result<String, Error> {
+    let script = compile(source)?;
+    Ok(dis_chunk(&script.chunk, "script"))
+}
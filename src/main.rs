@@ -1,14 +1,169 @@
-use scanner::Scanner;
+use std::io::{IsTerminal, Read, Write};
 
-mod bytecode;
-mod bytecode_interpreter;
-mod scanner;
-mod compiler;
-mod extensions;
+use test_cir::VM;
+
+/// What was asked for on the command line
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    // print the disassembly of the script before running it
+    dump: bool,
+
+    // fold constant expressions before running
+    optimize: bool,
+
+    // read and run a line at a time, the default when stdin is a terminal
+    repl: bool,
+
+    // the script to run, stdin when missing
+    path: Option<String>,
+}
+
+const USAGE: &str = "Usage: test_cir [--dump] [--optimize] [--repl] [script]";
+
+/// Parse the arguments that follow the program name
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+
+    for arg in args {
+        match arg.as_str() {
+            "--dump" => options.dump = true,
+            "--optimize" => options.optimize = true,
+            "--repl" => options.repl = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown flag '{}'.", flag)),
+            _ if options.path.is_some() => return Err(USAGE.to_string()),
+            _ => options.path = Some(arg),
+        }
+    }
+    if options.repl && options.path.is_some() {
+        return Err(USAGE.to_string());
+    }
+    Ok(options)
+}
+
+fn read_source(options: &Options) -> std::io::Result<String> {
+    match &options.path {
+        Some(path) => std::fs::read_to_string(path),
+        None => {
+            let mut source = String::new();
+            std::io::stdin().read_to_string(&mut source)?;
+            Ok(source)
+        },
+    }
+}
+
+/// Compile `source` and run it on `vm`, shared by scripts and the REPL
+fn run(vm: &mut VM, options: &Options, source: &str) -> Result<(), test_cir::Error> {
+    let script = if options.optimize {
+        test_cir::compile_optimized(source)?
+    } else {
+        test_cir::compile(source)?
+    };
+    // the listing is of the code that runs, folded or not
+    if options.dump {
+        println!("{}", test_cir::dis_chunk(&script.chunk, "script"));
+    }
+    vm.interpret_with_source(script, source).map_err(test_cir::Error::Runtime)?;
+    Ok(())
+}
+
+/// Run stdin a line at a time until it ends. Every line runs on the same
+/// VM so globals carry over, and errors are reported without stopping
+fn repl(options: &Options) {
+    let mut vm = VM::new();
+    let stdin = std::io::stdin();
+    let mut line = String::new();
+
+    loop {
+        print!("> ");
+        // the prompt is cosmetic, a failed flush isn't worth stopping for
+        let _ = std::io::stdout().flush();
+
+        line.clear();
+        match stdin.read_line(&mut line) {
+            // Ctrl-D, leave the shell prompt on a line of its own
+            Ok(0) => {
+                println!();
+                break;
+            },
+            Ok(_) => {},
+            Err(err) => {
+                eprintln!("Could not read input: {}", err);
+                break;
+            },
+        }
+
+        if let Err(err) = run(&mut vm, options, &line) {
+            eprintln!("{}", err);
+        }
+    }
+}
 
 fn main() {
-    let mut scanner = Scanner::default();
-    let test_code = "123.32";
-    scanner.scan_tokens(test_code.to_string());
-    println!("{:#?}", scanner);
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(64);
+        },
+    };
+
+    if options.path.is_none() && (options.repl || std::io::stdin().is_terminal()) {
+        repl(&options);
+        return;
+    }
+
+    let source = match read_source(&options) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Could not read '{}': {}", options.path.as_deref().unwrap_or("stdin"), err);
+            std::process::exit(74);
+        },
+    };
+
+    if let Err(err) = run(&mut VM::new(), &options, &source) {
+        eprintln!("{}", err);
+        // the exit codes clox uses, from sysexits.h
+        let code = match err {
+            test_cir::Error::Scan(_) | test_cir::Error::Compile(_) => 65,
+            test_cir::Error::Runtime(_) => 70,
+        };
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_args_reads_the_dump_flag_and_path() {
+        assert_eq!(parse_args(args(&[])), Ok(Options::default()));
+        assert_eq!(
+            parse_args(args(&["--dump", "main.lox"])),
+            Ok(Options {
+                dump: true,
+                path: Some("main.lox".to_string()),
+                ..Default::default()
+            })
+        );
+        assert!(parse_args(args(&["--repl"])).unwrap().repl);
+        assert!(parse_args(args(&["--optimize"])).unwrap().optimize);
+        assert!(parse_args(args(&["--repl", "main.lox"])).is_err());
+        assert!(parse_args(args(&["--verbose"])).is_err());
+        assert!(parse_args(args(&["a.lox", "b.lox"])).is_err());
+    }
+
+    #[test]
+    fn dump_disassembles_the_script() {
+        let options = parse_args(args(&["--dump"])).unwrap();
+        assert!(options.dump);
+
+        let output = test_cir::dump("print 1+1;").unwrap();
+        assert!(output.contains("OP_ADD"));
+        assert!(output.contains("OP_PRINT"));
+    }
 }
@@ -1,5 +1,7 @@
 use std::{collections::HashMap, fmt::Debug};
 
+use crate::extensions::Extensions;
+
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub enum TokenType {
@@ -17,6 +19,13 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
+    Question,
+    Colon,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     // One or two character tokens.
     Bang,
@@ -27,10 +36,14 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    ShiftLeft,
+    ShiftRight,
+    Arrow,
 
     // Literals.
     Identifier,
     String,
+    Char,
     Number,
 
     // Keywords.
@@ -51,15 +64,96 @@ pub enum TokenType {
     Var,
     While,
     Lambda,
+    Switch,
+    Case,
+    Default,
+    Break,
+    Continue,
+    Del,
 
     Eof,
 }
 
+/// How the token looks in source, or what kind of thing it is for the
+/// tokens that don't have a single spelling
+impl std::fmt::Display for TokenType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TokenType::*;
+
+        let text = match self {
+            LeftParen   => "(",
+            RightParen  => ")",
+            LeftBrace   => "{",
+            RightBrace  => "}",
+            LeftBracket => "[",
+            RightBracket    => "]",
+            Comma   => ",",
+            Dot     => ".",
+            Minus   => "-",
+            Plus    => "+",
+            Semicolon   => ";",
+            Slash   => "/",
+            Star    => "*",
+            Percent => "%",
+            Question    => "?",
+            Colon   => ":",
+            Ampersand   => "&",
+            Pipe    => "|",
+            Caret   => "^",
+            Tilde   => "~",
+            Bang    => "!",
+            BangEqual   => "!=",
+            Equal   => "=",
+            EqualEqual  => "==",
+            Greater => ">",
+            GreaterEqual    => ">=",
+            Less    => "<",
+            LessEqual   => "<=",
+            ShiftLeft   => "<<",
+            ShiftRight  => ">>",
+            Arrow   => "->",
+            Identifier  => "identifier",
+            String  => "string",
+            Char    => "character",
+            Number  => "number",
+            And     => "and",
+            Class   => "class",
+            Else    => "else",
+            False   => "false",
+            Fun     => "fun",
+            For     => "for",
+            If      => "if",
+            Nil     => "nil",
+            Or      => "or",
+            Print   => "print",
+            Return  => "return",
+            Super   => "super",
+            This    => "this",
+            True    => "true",
+            Var     => "var",
+            While   => "while",
+            Lambda  => "lambda",
+            Switch  => "switch",
+            Case    => "case",
+            Default => "default",
+            Break   => "break",
+            Continue    => "continue",
+            Del     => "del",
+            Eof     => "end of file",
+        };
+        write!(f, "{}", text)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Literal {
     Identifier(String),
     Str(String),
-    Number(f64)
+    // a number written with a decimal point
+    Number(f64),
+    // a number written without one, as long as it fits
+    Int(i64),
+    Char(char),
 }
 
 #[derive(Clone)]
@@ -67,7 +161,7 @@ pub struct Token {
     pub t_type: TokenType,
 
     // lexeme is the String value of the Token as it is
-    pub lexeme: Vec<u8>,
+    pub lexeme: String,
     
     // it may or may not be a literal
     pub literal: Option<Literal>,
@@ -75,8 +169,15 @@ pub struct Token {
     // line number
     pub line: usize, 
 
-    // column number 
-    pub col: usize 
+    // column number, of the last character of the lexeme
+    pub col: usize,
+
+    // column of the first character of the lexeme, 1-based
+    pub start_col: usize,
+
+    // byte range of the lexeme in the source, end is exclusive
+    pub start_byte: usize,
+    pub end_byte: usize,
 }
 
 impl Debug for Token {
@@ -85,7 +186,7 @@ impl Debug for Token {
             f,
             "Token {{ ty: {:?}, lexeme: \"{}\", literal: {:?}, line: {:?}, col: {:?}}}",
             self.t_type,
-            String::from_utf8(self.lexeme.clone()).unwrap(),
+            self.lexeme,
             self.literal,
             self.line,
             self.col,
@@ -93,15 +194,101 @@ impl Debug for Token {
     }
 }
 
+/// Lay the tokens out as a table with one token per row, for looking at
+/// what the scanner produced
+pub fn dump_tokens(tokens: &[Token]) -> String {
+    let rows: Vec<[String; 4]> = tokens
+        .iter()
+        .map(|token| [
+            format!("{:?}", token.t_type),
+            token.lexeme.clone(),
+            match &token.literal {
+                Some(literal) => format!("{:?}", literal),
+                None => "-".to_string(),
+            },
+            format!("{}:{}", token.line, token.col),
+        ])
+        .collect();
+
+    let header = [
+        "TYPE".to_string(),
+        "LEXEME".to_string(),
+        "LITERAL".to_string(),
+        "LINE:COL".to_string(),
+    ];
+
+    // every column is as wide as its widest cell
+    let mut widths = [0; 4];
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
 
-#[derive(Debug)]
+    std::iter::once(&header)
+        .chain(rows.iter())
+        .map(|row| format!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0], row[1], row[2], row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        ))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[derive(Debug, Clone)]
 pub struct Error {
-    info: String,
-    line: usize,
-    col: usize,
-    line_text: String,
+    pub info: String,
+    pub line: usize,
+    pub col: usize,
+
+    // the whole source line the error is on
+    pub line_text: String,
+
+    // byte range of the offending text in the source, end is exclusive
+    pub start_byte: usize,
+    pub end_byte: usize,
+
+    // byte offset of `line_text` in the source
+    line_start: usize,
+}
+
+impl Error {
+    /// A `^~~~` marking the span under `line_text`. A span that starts on
+    /// an earlier line is marked from the start of this one
+    fn underline(&self) -> String {
+        let start = self.start_byte.saturating_sub(self.line_start).min(self.line_text.len());
+        let end = self.end_byte.saturating_sub(self.line_start).clamp(start, self.line_text.len());
+
+        // keep tabs so the marker lines up however they are shown
+        let indent: String = self.line_text[.. start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = self.line_text[start .. end].chars().count();
+
+        format!("{}^{}", indent, "~".repeat(width.saturating_sub(1)))
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[ERROR] - {} \n {} \n {} \n at {}:{}(line:col)",
+            self.info,
+            self.line_text,
+            self.underline(),
+            self.line,
+            self.col,
+        )
+    }
 }
 
+impl std::error::Error for Error {}
+
 
 #[derive(Debug)]
 pub struct Scanner {
@@ -113,10 +300,8 @@ pub struct Scanner {
     tokens: Vec<Token>,
 
 
-    // since Error string is only going to be used
-    // for displaying the Error, we can borrow it with 
-    // the same lifetime as the scanner borrows the line_text
-    err: Option<Error>,
+    // every error found so far, scanning carries on past each of them
+    errors: Vec<Error>,
 
     // this is not the start position of the text
     // but rather the start position of the current
@@ -132,9 +317,22 @@ pub struct Scanner {
     // column number
     col:  usize,
 
+    // column the token at `start` begins at
+    start_col: usize,
+
+    // how many columns a '\t' moves `col` by
+    tab_width: usize,
+
     line_string: Vec<String>,
 
     keywords: HashMap<String, TokenType>,
+
+    // `(`, `{` and `[` still waiting for their closer, with their line
+    // and col, innermost last
+    brackets: Vec<(char, usize, usize)>,
+
+    // syntax beyond plain Lox that we are allowed to produce tokens for
+    extensions: Extensions,
 }
 
 
@@ -143,42 +341,126 @@ impl Default for Scanner {
         Self {
             source: Vec::with_capacity(100),
             tokens: Vec::with_capacity(100),
-            err: None,
+            errors: Vec::new(),
             start: 0,
             current: 0, 
             line: 1,
             col: 0,
-            // Take the keywords and the TokenType
-            // convert them into Rust HashMap
-            keywords: vec![
-                ("and", TokenType::And),
-                ("class", TokenType::Class),
-                ("else", TokenType::Else),
-                ("false", TokenType::False),
-                ("for", TokenType::For),
-                ("fun", TokenType::Fun),
-                 ("if", TokenType::If),
-                ("nil", TokenType::Nil),
-                ("or", TokenType::Or),
-                ("print", TokenType::Print),
-                ("return", TokenType::Return),
-                ("super", TokenType::Super),
-                ("this", TokenType::This),
-                ("true", TokenType::True),
-                ("var", TokenType::Var),
-                ("while", TokenType::While),
-                ("lambda", TokenType::Lambda)
-            ]
-            .into_iter()
-            .map(|(x,y)| (x.to_string(), y))
-            .collect(),
+            start_col: 1,
+            tab_width: 1,
+            keywords: Scanner::default_keywords(),
+            brackets: Vec::new(),
             line_string: Vec::with_capacity(100),
+            extensions: Extensions::default(),
         }
     }
 }
 
+impl IntoIterator for Scanner {
+    type Item = Token;
+    type IntoIter = std::vec::IntoIter<Token>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_tokens().into_iter()
+    }
+}
+
 impl Scanner {
+    /// The keywords of Lox and of our extensions, a starting point for
+    /// `with_keywords`
+    pub fn default_keywords() -> HashMap<String, TokenType> {
+        // Take the keywords and the TokenType
+        // convert them into Rust HashMap
+        vec![
+            ("and", TokenType::And),
+            ("class", TokenType::Class),
+            ("else", TokenType::Else),
+            ("false", TokenType::False),
+            ("for", TokenType::For),
+            ("fun", TokenType::Fun),
+            ("if", TokenType::If),
+            ("nil", TokenType::Nil),
+            ("or", TokenType::Or),
+            ("print", TokenType::Print),
+            ("return", TokenType::Return),
+            ("super", TokenType::Super),
+            ("this", TokenType::This),
+            ("true", TokenType::True),
+            ("var", TokenType::Var),
+            ("while", TokenType::While),
+            ("lambda", TokenType::Lambda),
+            ("switch", TokenType::Switch),
+            ("case", TokenType::Case),
+            ("default", TokenType::Default),
+            ("break", TokenType::Break),
+            ("continue", TokenType::Continue),
+            ("del", TokenType::Del),
+        ]
+        .into_iter()
+        .map(|(x,y)| (x.to_string(), y))
+        .collect()
+    }
+
+    /// A scanner for a dialect that spells its keywords differently. The
+    /// map replaces the default one, anything not in it is an identifier
+    pub fn with_keywords(keywords: HashMap<String, TokenType>) -> Self {
+        Scanner {
+            keywords,
+            ..Default::default()
+        }
+    }
+
+    /// A scanner that refuses the tokens of disabled extensions
+    pub fn with_extensions(extensions: Extensions) -> Self {
+        Scanner {
+            extensions,
+            ..Default::default()
+        }
+    }
+
+    /// Count a hard tab as `width` columns, to line diagnostics up with
+    /// editors that render tabs wider than one space
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width;
+    }
+
+    pub fn new() -> Scanner {
+        Scanner::default()
+    }
+
+    /// Scan `input` and print every error to stderr, for the CLI. The
+    /// results stay in the scanner, see `tokens` and `errors`
     pub fn scan_tokens(&mut self, input: String) {
+        self.scan_source(input);
+
+        for err in &self.errors {
+            eprintln!("{}", err);
+        }
+    }
+
+    /// Scan `input` without printing anything. Hands back the tokens, or
+    /// every error found when there was at least one
+    pub fn scan(&mut self, input: String) -> Result<Vec<Token>, Vec<Error>> {
+        self.scan_source(input);
+
+        if self.errors.is_empty() {
+            Ok(self.tokens.clone())
+        } else {
+            Err(self.errors.clone())
+        }
+    }
+
+    fn scan_source(&mut self, input: String) {
+        // each call starts from scratch, only the settings carry over
+        self.tokens.clear();
+        self.errors.clear();
+        self.brackets.clear();
+        self.start = 0;
+        self.current = 0;
+        self.line = 1;
+        self.col = 0;
+        self.start_col = 1;
+
         self.source = input.as_bytes().to_vec();
         self.line_string = input
             .lines()
@@ -187,12 +469,41 @@ impl Scanner {
         
         while !self.done() {
             self.start = self.current;
+            self.start_col = self.col + 1;
             self.scan_token();
         }
 
-        if let Some(err) = &self.err {
-            eprintln!("[ERROR] - {} \n {} \n at {}:{}(line:col)", err.info,err.line_text, err.line, err.col);
-        }
+        // a parser can always count on finding the end, even of empty input
+        self.tokens.push(Token {
+            t_type: TokenType::Eof,
+            lexeme: String::new(),
+            literal: None,
+            line: self.line,
+            col: self.col,
+            start_col: self.col + 1,
+            start_byte: self.current,
+            end_byte: self.current,
+        });
+    }
+
+    /// The tokens produced by the last call to `scan_tokens` or `scan`
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Hand over the tokens, the scanner isn't needed after that
+    pub fn into_tokens(self) -> Vec<Token> {
+        self.tokens
+    }
+
+    /// The first error found by the last scan, if any
+    pub fn error(&self) -> Option<&Error> {
+        self.errors.first()
+    }
+
+    /// Every error found by the last scan, in source order
+    pub fn errors(&self) -> &[Error] {
+        &self.errors
     }
 
     fn scan_token(&mut self) {
@@ -210,10 +521,23 @@ impl Scanner {
             ']' => self.add_token(RightBracket),
             ',' => self.add_token(Comma),
             '.' => self.add_token(Dot),
-            '-' => self.add_token(Minus),
+            '-' => {
+                if self.matches('>') {
+                    self.add_token(Arrow);
+                } else {
+                    self.add_token(Minus);
+                }
+            },
             '+' => self.add_token(Plus),
             ';' => self.add_token(Semicolon),
             '*' => self.add_token(Star),
+            '%' => self.add_token(Percent),
+            '&' => self.add_token(Ampersand),
+            '|' => self.add_token(Pipe),
+            '^' => self.add_token(Caret),
+            '~' => self.add_token(Tilde),
+            '?' => self.add_token(Question),
+            ':' => self.add_token(Colon),
             '!' => {
                 let match_result = self.matches('=');
 
@@ -239,6 +563,8 @@ impl Scanner {
 
                 if match_result {
                     self.add_token(LessEqual);
+                } else if self.matches('<') {
+                    self.add_token(ShiftLeft);
                 } else {
                     self.add_token(Less);
                 }
@@ -249,6 +575,9 @@ impl Scanner {
                 if match_result {
                     self.add_token(GreaterEqual);
                 }
+                else if self.matches('>') {
+                    self.add_token(ShiftRight);
+                }
                 else {
                     self.add_token(Greater);
                 }
@@ -278,7 +607,8 @@ impl Scanner {
             '"' => {
                 // Handle the case of a String
                 self.string();
-            }
+            },
+            '\'' => self.char_literal(),
             _ => {
                 if Scanner::is_decimal_digit(c) {
                     // it's a decimal number
@@ -302,9 +632,7 @@ impl Scanner {
             self.advance();
         }
 
-        let token_string = String::from_utf8(
-            self.source[self.start .. self.current].to_vec()
-        ).unwrap();
+        let token_string = self.current_text();
 
         let token_type = match self.keywords.get(&token_string) {
             Some(token_type)     =>  *token_type,
@@ -323,7 +651,7 @@ impl Scanner {
     }
     /// Checks if the character is alphanumeric
     fn is_alphanumeric(c: char) -> bool {
-        Scanner::is_alpha(c) || Scanner::is_alpha(c)
+        Scanner::is_alpha(c) || Scanner::is_decimal_digit(c)
     }
    
     /// Handle parsing of the number here
@@ -332,57 +660,141 @@ impl Scanner {
         // to be a decimal digit. So the numbers ahead can be either a float
         // or a long decimal number
 
+        // `0x`, `0o` and `0b` start an integer in another base
+        if self.source[self.start] == b'0' {
+            let radix = match self.peek() {
+                'x' | 'X' => Some(16),
+                'o' | 'O' => Some(8),
+                'b' | 'B' => Some(2),
+                _ => None,
+            };
+            if let Some(radix) = radix {
+                self.advance();
+                self.radix_number(radix);
+                return;
+            }
+        }
+
         // Our start position is kept in self.start so don't worry about that
-        while Scanner::is_decimal_digit(self.peek()) {
-            self.advance(); // keep advancing
+        if !self.digits() {
+            return;
         }
 
         // next character is a point maybe we are looking at a float ?
         // So look at the character +2 ahead than the current
         // say if ex: 22.30 then you found the '.' to be next, if you look ahead
         // than that, it would be '3' at self.peek_next()
-        if self.peek() == '.' && Scanner::is_decimal_digit(self.peek_next()) {
+        if self.peek() == '.' && self.peek_next() == '_' {
             self.advance();
+            self.set_error("`_` in a number must sit between two digits".to_string());
+            self.skip_rest_of_number();
+            return;
         }
+        if self.peek() == '.' && Scanner::is_decimal_digit(self.peek_next()) {
+            self.advance();
 
-        // After our one decimal we are sure it's a float, and even if it's a 
-        // decimal (based on our first while) then we are considering it float 
-        // as well since Lox keeps numbers as float(always)
+            // After our one decimal we are sure it's a float
+            if !self.digits() {
+                return;
+            }
+        }
 
-        while Scanner::is_decimal_digit(self.peek()) {
+        // an exponent, `1e10`, `1.5E+2` or `3e-4`
+        if self.peek() == 'e' || self.peek() == 'E' {
             self.advance();
-        }
+            if self.peek() == '+' || self.peek() == '-' {
+                self.advance();
+            }
 
-        if !Scanner::is_decimal_digit(self.peek()) {
-            // if it is not a decimal digit
-            if self.peek() != '\0' {
-                self.set_error(format!("Invalid string at the end of the number: `{}`", self.peek()));
+            if !Scanner::is_decimal_digit(self.peek()) {
+                self.set_error("Exponent of the number has no digits".to_string());
+                self.skip_rest_of_number();
                 return;
             }
+            if !self.digits() {
+                return;
+            }
+        }
+
+        if Scanner::is_alpha(self.peek()) {
+            // a number running straight into letters, like `123abc`
+            self.set_error(format!("Invalid string at the end of the number: `{}`", self.peek()));
+
+            // skip the letters too, they aren't a token of their own
+            self.skip_rest_of_number();
+            return;
         }
 
-        let val = match String::from_utf8(
-            self.source[self.start .. self.current].to_vec()
-        ) {
-            Ok(str)   => {
-                match str.parse::<f64>() {
-                    Ok(float) => float,
-                    Err(float_e)   => {
-                        self.set_error(float_e.to_string());
-                        return;
-                    } 
+        // the separators are only there for the reader
+        let text = self.current_text().replace('_', "");
+
+        // integral literals stay integers so `5` and `5.0` can be
+        // told apart, too big ones fall back to a float
+        let literal = match text.parse::<i64>() {
+            Ok(int) => Literal::Int(int),
+            Err(_) => match text.parse::<f64>() {
+                Ok(float) => Literal::Number(float),
+                Err(float_e)   => {
+                    self.set_error(float_e.to_string());
+                    return;
                 }
             },
-            Err(e) => {
-                // utf8 conversion error
-                // Error to return
-                self.set_error(e.to_string());
-                return;
-            },
         };
 
-        self.add_token_literal(TokenType::Number, Some(Literal::Number(val)))
+        self.add_token_literal(TokenType::Number, Some(literal))
+
+    }
+
+    /// The digits of a `0x`, `0o` or `0b` literal, the prefix is consumed
+    fn radix_number(&mut self, radix: u32) {
+        let digits_start = self.current;
+        self.skip_rest_of_number();
+
+        // decoded as text, so a letter like `é` shows up whole in the error
+        let digits: String = String::from_utf8_lossy(&self.source[digits_start .. self.current])
+            .chars()
+            .filter(|c| *c != '_')
+            .collect();
+
+        if let Some(bad) = digits.chars().find(|c| !c.is_digit(radix)) {
+            self.set_error(format!("Invalid digit `{}` in a base {} number", bad, radix));
+            return;
+        }
+
+        match i64::from_str_radix(&digits, radix) {
+            Ok(int) => self.add_token_literal(TokenType::Number, Some(Literal::Int(int))),
+            Err(_) if digits.is_empty() => {
+                self.set_error(format!("Base {} number has no digits", radix));
+            },
+            Err(err) => self.set_error(err.to_string()),
+        }
+    }
 
+    /// Consume a run of digits, which may be split up by single `_`s like
+    /// `1_000_000`. The first digit has been checked by the caller. Returns
+    /// false with the error set for a `_` that isn't between two digits
+    fn digits(&mut self) -> bool {
+        while Scanner::is_decimal_digit(self.peek()) || self.peek() == '_' {
+            if self.peek() == '_' {
+                let previous = char::from(self.source[self.current - 1]);
+                if !Scanner::is_decimal_digit(previous) || !Scanner::is_decimal_digit(self.peek_next()) {
+                    self.advance();
+                    self.set_error("`_` in a number must sit between two digits".to_string());
+                    self.skip_rest_of_number();
+                    return false;
+                }
+            }
+            self.advance();
+        }
+        true
+    }
+
+    /// After a malformed number, skip what is left of it so it doesn't
+    /// turn into more tokens
+    fn skip_rest_of_number(&mut self) {
+        while Scanner::is_alphanumeric(self.peek()) {
+            self.advance();
+        }
     }
 
     /// Take error string, get current line, and set the error
@@ -393,96 +805,214 @@ impl Scanner {
             None    => "".to_string(),
         };
 
+        // the current line starts after the last newline scanned
+        let line_start = self.source[.. self.current]
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |idx| idx + 1);
+
         let error = Error {
             info: error_string,
             line: self.line,
             col: self.col,
             line_text: current_line_text,
+            start_byte: self.start,
+            end_byte: self.current,
+            line_start,
         };
 
-        self.err = Some(error);
+        self.errors.push(error);
 
     }
 
     /// Scan for a string and store as a Token
     fn string(&mut self){
 
+        // the decoded contents, escapes already replaced
+        let mut value = String::new();
+
+        // a bad escape is reported where it is, but we still scan up to
+        // the closing quote so the rest of the string isn't read as code
+        let mut valid = true;
+
         // until you find the ending of the string and till the source code is ended
         // keep iterating
         // This code allows multiline strings
         while self.peek() != '"' && !self.is_at_end() {
-            self.advance();
+            match self.advance() {
+                '\n' => {
+                    // keep line and col right for the tokens after the string
+                    self.line += 1;
+                    self.col = 0;
+                    value.push('\n');
+                },
+                '\\' => {
+                    if !self.escape(&mut value) {
+                        valid = false;
+                    }
+                },
+                c => value.push(c),
+            }
         }
 
-        if self.is_at_end() {
-            // Get the text of the current line
-
-            let line_text = match self.get_current_line() {
-                Some(str) => str.to_string(),
-                None    => "".to_string(),
-            };
-            self.err = Some(
-                Error {
-                    info: "Unterminated string found".to_string(),
-                    line: self.line,
-                    col: self.col,
-                    line_text,
-                }
-            );
+        // Running out of source is the only way to get here without the
+        // closing double quote, report it rather than asserting
+        if self.is_at_end() || self.peek() != '"' {
+            self.set_error("Unterminated string found".to_string());
             return;
         }
 
-        // can we remove this assert?
-        // Ans: we sure can, but it is for the edge cases that we might ignore
-        // the termination double quote
-        assert!(self.peek() == '"');
-
         // Why this advance here exactly?
         // Ans: To also increment the '"' token
         self.advance();
 
-        
+        if !valid {
+            return;
+        }
+
         self.add_token_literal(
             
             // this TokenType is String
             TokenType::String,
-            Some(Literal::Str(
-
-                // Create the String from the raw u8 bytes
-                String::from_utf8(
-                    self.source[self.start + 1 .. self.current - 1].to_vec()
-                ).unwrap()
-            ))
+            Some(Literal::Str(value))
         );
     }
 
+    /// `'c'`, exactly one character, escapes work like in strings
+    fn char_literal(&mut self) {
+        let mut value = String::new();
+        let mut valid = true;
+
+        while self.peek() != '\'' && self.peek() != '\n' && !self.is_at_end() {
+            match self.advance() {
+                '\\' => valid &= self.escape(&mut value),
+                c => value.push(c),
+            }
+        }
+
+        if self.peek() != '\'' {
+            self.set_error("Unterminated character literal".to_string());
+            return;
+        }
+        self.advance();
+
+        if !valid {
+            return;
+        }
+
+        let mut chars = value.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => self.add_token_literal(TokenType::Char, Some(Literal::Char(c))),
+            (None, _) => self.set_error("Empty character literal".to_string()),
+            (Some(_), Some(_)) => {
+                self.set_error("Character literal must hold exactly one character".to_string())
+            },
+        }
+    }
+
+    /// Decode the escape after a `\` into `value`. Besides the usual single
+    /// character ones, `\xHH` is the character with that code below 256
+    /// and `\u{H...}` any Unicode scalar value. Returns false with the
+    /// error set for unknown or malformed ones
+    fn escape(&mut self, value: &mut String) -> bool {
+        if self.is_at_end() {
+            // the missing closing quote gets reported instead
+            return true;
+        }
+
+        let simple = match self.peek() {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            '\\' => Some('\\'),
+            '"' => Some('"'),
+            '\'' => Some('\''),
+            '0' => Some('\0'),
+            _ => None,
+        };
+        if let Some(c) = simple {
+            self.advance();
+            value.push(c);
+            return true;
+        }
+
+        let code = match self.peek() {
+            'x' => {
+                self.advance();
+                let digits = self.hex_digits(2);
+                if digits.len() != 2 {
+                    self.set_error("Invalid escape: `\\x` needs two hex digits".to_string());
+                    return false;
+                }
+                u32::from_str_radix(&digits, 16).ok()
+            },
+            'u' => {
+                self.advance();
+                if !self.matches('{') {
+                    self.set_error("Invalid escape: expected `{` after `\\u`".to_string());
+                    return false;
+                }
+                let digits = self.hex_digits(6);
+                if digits.is_empty() || !self.matches('}') {
+                    self.set_error("Invalid escape: `\\u{...}` needs 1 to 6 hex digits".to_string());
+                    return false;
+                }
+                u32::from_str_radix(&digits, 16).ok()
+            },
+            other => {
+                // a newline is left for the caller, which keeps count of lines
+                if other != '\n' {
+                    self.advance();
+                }
+                self.set_error(format!("Unknown escape sequence `\\{}`", other.escape_debug()));
+                return false;
+            },
+        };
+
+        match code.and_then(char::from_u32) {
+            Some(c) => {
+                value.push(c);
+                true
+            },
+            None => {
+                self.set_error("Invalid escape: not a Unicode scalar value".to_string());
+                false
+            },
+        }
+    }
+
+    /// Consume up to `max` hex digits and return them
+    fn hex_digits(&mut self, max: usize) -> String {
+        let mut digits = String::new();
+        while digits.len() < max && self.peek().is_ascii_hexdigit() {
+            digits.push(self.advance());
+        }
+        digits
+    }
+
 
     /// This function gets the current line in the form of a String
+    /// `None` past the last line, e.g. for empty input
     fn get_current_line(&self) -> Option<&String> {
-        self.line_string.get(self.line - 1)
+        let idx = self.line.checked_sub(1)?;
+        self.line_string.get(idx)
     }
 
     /// Peek the next character without increasing the count or incrementing the tokenizer
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            char::from(self.source[self.current])
-        }
+        self.char_at(self.current).unwrap_or('\0')
     }
 
     /// If at the end return true, if next char doesn't match the `c`
     /// then return false, else increase the counts and return true
     fn matches(&mut self, c: char) -> bool {
 
-        if self.is_at_end() {
-            return false
-        } else if self.peek_next() != c {
+        if self.is_at_end() || self.peek() != c {
             return false
         }
         
         self.col += 1;
-        self.current += 1;
+        self.current += c.len_utf8();
         true
     }
 
@@ -493,18 +1023,43 @@ impl Scanner {
     /// To get next character without incrementing any counts
     /// or consuming any value
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        }
-        else {
-            char::from(self.source[self.current + 1])
-        }
+        self.char_at(self.current + self.peek().len_utf8()).unwrap_or('\0')
+    }
+
+    /// The source text of the token being scanned
+    fn current_text(&self) -> String {
+        String::from_utf8_lossy(&self.source[self.start .. self.current]).to_string()
+    }
+
+    /// The character whose UTF-8 encoding starts at byte `idx` of the
+    /// source, `None` past the end
+    fn char_at(&self, idx: usize) -> Option<char> {
+        let rest = self.source.get(idx..)?;
+        let bytes = &rest[..rest.len().min(4)];
+        let text = match std::str::from_utf8(bytes) {
+            Ok(text) => text,
+            // the next character is whole, what follows it may not be
+            Err(err) => std::str::from_utf8(&bytes[..err.valid_up_to()]).ok()?,
+        };
+        text.chars().next()
     }
 
     fn add_token_literal(&mut self, token_type : TokenType, literal: Option<Literal>) {
 
-        // text of Token in Vec<u8>
-        let text = self.source[self.start .. self.current].to_vec();
+        let text = self.current_text();
+
+        if let Some(feature) = self.extensions.disabled_by(token_type) {
+            self.set_error(format!(
+                "`{}` needs the '{}' feature, which is not enabled",
+                text,
+                feature
+            ));
+            return;
+        }
+
+        if !self.track_bracket(token_type) {
+            return;
+        }
 
         self.tokens.push(
             Token {
@@ -512,13 +1067,48 @@ impl Scanner {
                 lexeme: text,
                 literal, 
                 line: self.line,
-                col:self.col
+                col:self.col,
+                start_col: self.start_col,
+                start_byte: self.start,
+                end_byte: self.current,
             }
         )
 
     }
+    /// Keep the stack of open brackets up to date. Returns false, with the
+    /// error set, when a closer doesn't match the latest opener
+    fn track_bracket(&mut self, token_type: TokenType) -> bool {
+        let (opener, closer) = match token_type {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => {
+                let c = char::from(self.source[self.start]);
+                self.brackets.push((c, self.line, self.col));
+                return true;
+            },
+            TokenType::RightParen   => ('(', ')'),
+            TokenType::RightBrace   => ('{', '}'),
+            TokenType::RightBracket => ('[', ']'),
+            _ => return true,
+        };
+
+        match self.brackets.pop() {
+            Some((open, _, _)) if open == opener => true,
+            Some((open, line, col)) => {
+                self.set_error(format!(
+                    "Mismatched bracket: `{}` at {}:{} doesn't close `{}` at {}:{}",
+                    closer, self.line, self.col, open, line, col
+                ));
+                false
+            },
+            None => {
+                self.set_error(format!("Unmatched `{}` at {}:{}", closer, self.line, self.col));
+                false
+            },
+        }
+    }
+
+    /// Letters and `_`, anything an identifier may start with
     fn is_alpha(c: char) -> bool {
-        c.is_alphabetic()
+        c.is_alphabetic() || c == '_'
     }
 
     fn is_decimal_digit(c: char) -> bool {
@@ -529,29 +1119,33 @@ impl Scanner {
     /// Get the current char
     pub fn cur_char(&self)  -> Option<char> {
         if self.done() {
-            return None;
+            None
         }
         else {
-            return Some(char::from(self.source[self.current - 1]));
+            // step back over the continuation bytes to where it starts
+            let start = (0..self.current)
+                .rev()
+                .find(|&idx| self.source[idx] & 0b1100_0000 != 0b1000_0000)?;
+            self.char_at(start)
         }
     }
 
     /// Advance to the next character and increment the counters
     pub fn advance(&mut self) -> char {
-        self.current += 1;
-        self.col += 1;
-        
-        // The whole reason we did a +1 before and -1 later
-        // was the analogy in our head that self.current is 0
-        // in the program but in our head it is self.current = 1
-        char::from(self.source[self.current - 1])
-
+        // `current` is a byte offset, step over every byte of the
+        // character so it always sits on a character boundary
+        let c = self.char_at(self.current).unwrap_or('\0');
+        self.current += c.len_utf8();
+
+        // columns count characters, not bytes
+        self.col += if c == '\t' { self.tab_width } else { 1 };
+        c
     }
 
-    /// If we got an error or are at the end
-    /// then we are done
+    /// Errors don't stop the scan, we are done
+    /// once we are at the end
     pub fn done(&self) -> bool {
-        self.err.is_some() || self.is_at_end()
+        self.is_at_end()
     }
 
     /// if current pointer is greater than len of the source of text
@@ -559,4 +1153,575 @@ impl Scanner {
     pub fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_input_scans_to_just_eof() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(String::new());
+
+        assert_eq!(scanner.tokens().len(), 1);
+        assert_eq!(scanner.tokens()[0].t_type, TokenType::Eof);
+        assert!(scanner.error().is_none());
+        assert_eq!(scanner.get_current_line(), None);
+    }
+
+    #[test]
+    fn error_display_shows_info_source_and_position() {
+        let error = Error {
+            info: "Unterminated string found".to_string(),
+            line: 3,
+            col: 7,
+            line_text: "print \"oops;".to_string(),
+            start_byte: 26,
+            end_byte: 32,
+            line_start: 20,
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "[ERROR] - Unterminated string found \n print \"oops; \n       ^~~~~~ \n at 3:7(line:col)"
+        );
+    }
+
+    #[test]
+    fn error_underline_lines_up_with_the_bad_token() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("var a = 1;\nvar x = \"abcd".to_string());
+
+        let error = scanner.error().unwrap();
+        assert_eq!((error.start_byte, error.end_byte), (19, 24));
+        assert_eq!(error.underline(), "        ^~~~~");
+
+        let lines: Vec<String> = error.to_string().lines().map(|line| line.to_string()).collect();
+        let column = lines[2].find('^').unwrap();
+        assert_eq!(&lines[1][column .. column + 5], "\"abcd");
+
+        // tabs stay tabs and characters count once however many bytes
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("\té = @;".to_string());
+        assert_eq!(scanner.error().unwrap().underline(), "\t    ^");
+    }
+
+    #[test]
+    fn error_can_be_boxed_as_std_error() {
+        let error: Box<dyn std::error::Error> = Box::new(Error {
+            info: "Scanner can't handle: @".to_string(),
+            line: 1,
+            col: 1,
+            line_text: "@".to_string(),
+            start_byte: 0,
+            end_byte: 1,
+            line_start: 0,
+        });
+
+        assert!(!error.to_string().is_empty());
+    }
+
+    #[test]
+    fn disabled_extension_tokens_are_rejected() {
+        let mut scanner = Scanner::with_extensions(Extensions {
+            ternary: false,
+            ..Default::default()
+        });
+        scanner.scan_tokens("a ? b : c".to_string());
+
+        assert!(scanner.error().unwrap().info.contains("'ternary' feature"));
+    }
+
+    #[test]
+    fn dump_tokens_aligns_columns() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("x = 12".to_string());
+
+        let dump = dump_tokens(scanner.tokens());
+        let lines: Vec<&str> = dump.lines().collect();
+
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "TYPE        LEXEME  LITERAL          LINE:COL");
+        assert_eq!(lines[1], "Identifier  x       Identifier(\"x\")  1:1");
+        assert_eq!(lines[2], "Equal       =       -                1:3");
+        assert_eq!(lines[3], "Number      12      Int(12)          1:6");
+        assert_eq!(lines[4], "Eof                 -                1:6");
+    }
+
+    #[test]
+    fn integer_and_float_literals_are_distinct() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("5 5.0 99999999999999999999".to_string());
+        let tokens = scanner.tokens();
+
+        assert!(matches!(tokens[0].literal, Some(Literal::Int(5))));
+        assert!(matches!(tokens[1].literal, Some(Literal::Number(n)) if n == 5.0));
+        assert!(matches!(tokens[2].literal, Some(Literal::Number(n)) if n == 1e20));
+    }
+
+    #[test]
+    fn with_keywords_replaces_the_keyword_table() {
+        let mut keywords = Scanner::default_keywords();
+        keywords.remove("var");
+        keywords.insert("let".to_string(), TokenType::Var);
+
+        let mut scanner = Scanner::with_keywords(keywords);
+        scanner.scan_tokens("let x var".to_string());
+        let tokens = scanner.tokens();
+
+        assert_eq!(tokens[0].t_type, TokenType::Var);
+        assert_eq!(tokens[1].t_type, TokenType::Identifier);
+        assert_eq!(tokens[1].lexeme, "x");
+        assert_eq!(tokens[2].t_type, TokenType::Identifier);
+    }
+
+    #[test]
+    fn mismatched_brackets_are_reported_with_both_positions() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("foo(a]".to_string());
+
+        let error = scanner.error().unwrap();
+        assert_eq!(error.info, "Mismatched bracket: `]` at 1:6 doesn't close `(` at 1:4");
+
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("a)".to_string());
+        assert_eq!(scanner.error().unwrap().info, "Unmatched `)` at 1:2");
+    }
+
+    #[test]
+    fn a_scanner_can_be_reused() {
+        let mut scanner = Scanner::default();
+        assert!(scanner.scan("print (1 @ 2;".to_string()).is_err());
+
+        let tokens = scanner.scan("x".to_string()).unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|token| token.t_type).collect();
+        assert_eq!(types, vec![TokenType::Identifier, TokenType::Eof]);
+        assert_eq!((tokens[0].line, tokens[0].start_col, tokens[0].start_byte), (1, 1, 0));
+
+        // the open `(` from the first scan is forgotten too
+        assert!(scanner.scan(")".to_string()).unwrap_err()[0].info.starts_with("Unmatched"));
+    }
+
+    #[test]
+    fn brackets_inside_strings_are_ignored() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("f(\"(]\", [{}])".to_string());
+        assert!(scanner.error().is_none());
+    }
+
+    fn scan_string(source: &str) -> Result<String, Error> {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(source.to_string());
+        if let Some(error) = scanner.error() {
+            return Err(error.clone());
+        }
+
+        match &scanner.tokens()[0].literal {
+            Some(Literal::Str(value)) => Ok(value.clone()),
+            other => panic!("expected a string literal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hex_and_unicode_escapes_are_decoded() {
+        assert_eq!(scan_string(r#""\x41""#).unwrap(), "A");
+        assert_eq!(scan_string(r#""\u{1F600}""#).unwrap(), "\u{1F600}");
+        assert_eq!(scan_string(r#""a\u{e9}b""#).unwrap(), "a\u{e9}b");
+    }
+
+    #[test]
+    fn multi_byte_characters_in_strings_are_kept_whole() {
+        assert_eq!(scan_string("\"héllo 👍\"").unwrap(), "héllo 👍");
+        assert_eq!(scan_string("\"日本\\n語\"").unwrap(), "日本\n語");
+
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("\"👍\" x".to_string());
+        assert_eq!(scanner.tokens()[1].start_col, 5);
+    }
+
+    #[test]
+    fn simple_escapes_are_decoded() {
+        assert_eq!(scan_string(r#""a\nb""#).unwrap(), "a\nb");
+        assert_eq!(scan_string(r#""\t\r\0""#).unwrap(), "\t\r\0");
+        assert_eq!(scan_string(r#""say \"hi\" \\o/""#).unwrap(), "say \"hi\" \\o/");
+    }
+
+    #[test]
+    fn unknown_escapes_are_errors() {
+        let error = scan_string(r#""a\qb""#).unwrap_err();
+        assert_eq!(error.info, "Unknown escape sequence `\\q`");
+        assert_eq!(error.col, 4);
+    }
+
+    #[test]
+    fn escaped_newlines_keep_later_errors_in_place() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("var s = \"a\\\nb\";\nvar t = \"\\q\";".to_string());
+
+        let errors = scanner.errors();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].info, "Unknown escape sequence `\\\\n`");
+        assert_eq!((errors[0].line, errors[0].col), (1, 11));
+        assert_eq!(errors[0].line_text, "var s = \"a\\");
+        assert_eq!(errors[0].underline(), "        ^~~");
+
+        assert_eq!(errors[1].info, "Unknown escape sequence `\\q`");
+        assert_eq!((errors[1].line, errors[1].col), (3, 11));
+        assert_eq!(errors[1].line_text, "var t = \"\\q\";");
+    }
+
+    #[test]
+    fn malformed_escapes_are_errors() {
+        let error = scan_string(r#""ab\xZZ""#).unwrap_err();
+        assert!(error.info.contains("two hex digits"));
+        assert_eq!(error.col, 5);
+
+        let error = scan_string(r#""\u{}""#).unwrap_err();
+        assert!(error.info.contains("1 to 6 hex digits"));
+
+        let error = scan_string(r#""\u{D800}""#).unwrap_err();
+        assert!(error.info.contains("not a Unicode scalar value"));
+    }
+
+    #[test]
+    fn scanning_continues_past_errors() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("var a = @;\nvar b = 12ab;\nvar c = \"\\xZZ\" $ 3;".to_string());
+
+        let errors = scanner.errors();
+        assert_eq!(errors.len(), 4);
+        assert_eq!((errors[0].info.as_str(), errors[0].line), ("Scanner can't handle: @", 1));
+        assert_eq!(errors[1].line, 2);
+        assert!(errors[1].info.contains("end of the number"));
+        assert!(errors[2].info.contains("two hex digits"));
+        assert_eq!((errors[3].info.as_str(), errors[3].line), ("Scanner can't handle: $", 3));
+
+        // the tokens around the mistakes are still there
+        let tokens = scanner.tokens();
+        let last = &tokens[tokens.len() - 2];
+        assert_eq!(last.t_type, TokenType::Semicolon);
+        assert_eq!(last.line, 3);
+    }
+
+    #[test]
+    fn tokens_can_be_borrowed_or_taken() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("a + 1".to_string());
+        let borrowed: Vec<TokenType> = scanner.tokens().iter().map(|t| t.t_type).collect();
+
+        let owned: Vec<TokenType> = scanner.into_iter().map(|t| t.t_type).collect();
+        assert_eq!(owned, borrowed);
+        assert_eq!(owned[..3], [TokenType::Identifier, TokenType::Plus, TokenType::Number]);
+    }
+
+    #[test]
+    fn the_last_token_is_always_eof() {
+        for source in ["", "var a = 1;", "\"unterminated", "a\n\n"] {
+            let mut scanner = Scanner::default();
+            scanner.scan_tokens(source.to_string());
+
+            let last = scanner.tokens().last().unwrap();
+            assert_eq!(last.t_type, TokenType::Eof, "{:?}", source);
+            assert!(last.lexeme.is_empty());
+        }
+
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("a\nb".to_string());
+        let eof = scanner.tokens().last().unwrap();
+        assert_eq!((eof.line, eof.col), (2, 1));
+    }
+
+    #[test]
+    fn identifiers_take_digits_and_underscores() {
+        for name in ["foo2", "_x", "a1b2", "my_var", "_count"] {
+            let mut scanner = Scanner::default();
+            scanner.scan_tokens(name.to_string());
+            let tokens = scanner.tokens();
+
+            assert_eq!(tokens.len(), 2, "{}", name);
+            assert_eq!(tokens[0].t_type, TokenType::Identifier);
+            assert_eq!(tokens[0].lexeme, name);
+        }
+    }
+
+    #[test]
+    fn numbers_at_the_very_end_do_not_panic() {
+        for source in ["12", "x = 1.", "7"] {
+            let mut scanner = Scanner::default();
+            scanner.scan_tokens(source.to_string());
+
+            let tokens = scanner.tokens();
+            assert_eq!(tokens[tokens.len() - 1].t_type, TokenType::Eof);
+            assert!(tokens.iter().any(|t| t.t_type == TokenType::Number), "{}", source);
+        }
+    }
+
+    #[test]
+    fn tokens_know_the_column_they_start_at() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("var a;\n  foo = 12;".to_string());
+        let tokens = scanner.tokens();
+
+        let foo = &tokens[3];
+        assert_eq!((foo.line, foo.start_col, foo.col), (2, 3, 5));
+        let number = &tokens[5];
+        assert_eq!((number.start_col, number.col), (9, 10));
+    }
+
+    fn scan_number(source: &str) -> Result<f64, Error> {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(source.to_string());
+        if let Some(error) = scanner.error() {
+            return Err(error.clone());
+        }
+
+        match scanner.tokens()[0].literal {
+            Some(Literal::Number(value)) => Ok(value),
+            Some(Literal::Int(value)) => Ok(value as f64),
+            ref other => panic!("expected a number literal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scientific_notation_is_a_number() {
+        assert_eq!(scan_number("1e10").unwrap(), 1e10);
+        assert_eq!(scan_number("1.5E+2").unwrap(), 150.0);
+        assert_eq!(scan_number("3e-4").unwrap(), 3e-4);
+
+        let error = scan_number("1e").unwrap_err();
+        assert_eq!(error.info, "Exponent of the number has no digits");
+        assert!(scan_number("2e+x").is_err());
+    }
+
+    #[test]
+    fn underscores_separate_digits() {
+        assert_eq!(scan_number("1_000_000").unwrap(), 1_000_000.0);
+        assert_eq!(scan_number("12.345_678").unwrap(), 12.345_678);
+        assert_eq!(scan_number("1_0e1_0").unwrap(), 10e10);
+
+        for source in ["5_", "5__5", "1._5", "1_.5", "2e_5"] {
+            let error = scan_number(source).unwrap_err();
+            assert!(
+                error.info.contains("between two digits") || error.info.contains("no digits"),
+                "{}: {}", source, error.info
+            );
+        }
+
+        // a leading `_` makes a name, not a number
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("_5".to_string());
+        assert_eq!(scanner.tokens()[0].t_type, TokenType::Identifier);
+    }
+
+    #[test]
+    fn hex_octal_and_binary_literals() {
+        assert_eq!(scan_number("0xFF").unwrap(), 255.0);
+        assert_eq!(scan_number("0o17").unwrap(), 15.0);
+        assert_eq!(scan_number("0b1010").unwrap(), 10.0);
+        assert_eq!(scan_number("0b1111_0000").unwrap(), 240.0);
+
+        assert_eq!(scan_number("0b102").unwrap_err().info, "Invalid digit `2` in a base 2 number");
+        assert_eq!(scan_number("0o8").unwrap_err().info, "Invalid digit `8` in a base 8 number");
+        assert_eq!(scan_number("0xé").unwrap_err().info, "Invalid digit `é` in a base 16 number");
+        assert_eq!(scan_number("0x").unwrap_err().info, "Base 16 number has no digits");
+    }
+
+    #[test]
+    fn percent_is_its_own_token() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("a % b".to_string());
+        let types: Vec<TokenType> = scanner.tokens().iter().map(|t| t.t_type).collect();
+
+        assert_eq!(types, vec![
+            TokenType::Identifier,
+            TokenType::Percent,
+            TokenType::Identifier,
+            TokenType::Eof,
+        ]);
+    }
+
+    #[test]
+    fn bitwise_operators_scan() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("& | ^ << >> <= >".to_string());
+        let types: Vec<TokenType> = scanner.tokens().iter().map(|t| t.t_type).collect();
+
+        assert_eq!(types, vec![
+            TokenType::Ampersand,
+            TokenType::Pipe,
+            TokenType::Caret,
+            TokenType::ShiftLeft,
+            TokenType::ShiftRight,
+            TokenType::LessEqual,
+            TokenType::Greater,
+            TokenType::Eof,
+        ]);
+    }
+
+    #[test]
+    fn ternary_operators_scan() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("a?b:c".to_string());
+        let types: Vec<TokenType> = scanner.tokens().iter().map(|t| t.t_type).collect();
+
+        assert_eq!(types, vec![
+            TokenType::Identifier,
+            TokenType::Question,
+            TokenType::Identifier,
+            TokenType::Colon,
+            TokenType::Identifier,
+            TokenType::Eof,
+        ]);
+    }
+
+    fn scan_char(source: &str) -> Result<char, Error> {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(source.to_string());
+        if let Some(error) = scanner.error() {
+            return Err(error.clone());
+        }
+
+        assert_eq!(scanner.tokens()[0].t_type, TokenType::Char);
+        match scanner.tokens()[0].literal {
+            Some(Literal::Char(c)) => Ok(c),
+            ref other => panic!("expected a char literal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn character_literals() {
+        assert_eq!(scan_char("'a'").unwrap(), 'a');
+        assert_eq!(scan_char(r"'\n'").unwrap(), '\n');
+        assert_eq!(scan_char(r"'\''").unwrap(), '\'');
+        assert_eq!(scan_char(r"'\x41'").unwrap(), 'A');
+    }
+
+    #[test]
+    fn malformed_character_literals_are_errors() {
+        assert_eq!(scan_char("''").unwrap_err().info, "Empty character literal");
+        assert_eq!(scan_char("'a").unwrap_err().info, "Unterminated character literal");
+        assert_eq!(scan_char("'a\n'").unwrap_err().info, "Unterminated character literal");
+        assert_eq!(
+            scan_char("'ab'").unwrap_err().info,
+            "Character literal must hold exactly one character"
+        );
+    }
+
+    #[test]
+    fn scan_returns_tokens_or_errors() {
+        let tokens = Scanner::new().scan("a = 1;".to_string()).unwrap();
+        assert_eq!(tokens.len(), 5);
+        assert_eq!(tokens[4].t_type, TokenType::Eof);
+
+        let errors = Scanner::new().scan("a = @ # 1;".to_string()).unwrap_err();
+        let infos: Vec<&str> = errors.iter().map(|e| e.info.as_str()).collect();
+        assert_eq!(infos, vec!["Scanner can't handle: @", "Scanner can't handle: #"]);
+    }
+
+    #[test]
+    fn byte_spans_slice_out_the_lexeme() {
+        let source = "var name = \"a b\";\nprint name >= 0x1F;";
+        let tokens = Scanner::new().scan(source.to_string()).unwrap();
+
+        for token in &tokens {
+            assert_eq!(&source[token.start_byte..token.end_byte], token.lexeme);
+        }
+        assert_eq!(tokens.last().unwrap().start_byte, source.len());
+    }
+
+    #[test]
+    fn token_types_display_as_they_are_written() {
+        for (keyword, t_type) in Scanner::default_keywords() {
+            assert_eq!(t_type.to_string(), keyword);
+        }
+
+        assert_eq!(TokenType::LeftParen.to_string(), "(");
+        assert_eq!(TokenType::ShiftRight.to_string(), ">>");
+        assert_eq!(TokenType::Number.to_string(), "number");
+        assert_eq!(TokenType::String.to_string(), "string");
+    }
+
+    #[test]
+    fn tab_width_moves_the_column() {
+        let mut scanner = Scanner::default();
+        scanner.set_tab_width(4);
+        scanner.scan_tokens("\tx".to_string());
+
+        assert_eq!(scanner.tokens()[0].col, 5);
+
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("\tx".to_string());
+
+        assert_eq!(scanner.tokens()[0].col, 2);
+    }
+
+    #[test]
+    fn unicode_identifiers_scan_whole() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("café naïve".to_string());
+        assert!(scanner.error().is_none());
+
+        let tokens = scanner.tokens();
+        assert!(matches!(&tokens[0].literal, Some(Literal::Identifier(name)) if name == "café"));
+        assert!(matches!(&tokens[1].literal, Some(Literal::Identifier(name)) if name == "naïve"));
+    }
+
+    #[test]
+    fn lexemes_are_the_source_text() {
+        let source = "var café = 1_000 + \"a b\";";
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(source.to_string());
+
+        let tokens = scanner.tokens();
+        assert_eq!(tokens[1].lexeme, "café");
+        assert_eq!(tokens[3].lexeme, "1_000");
+        assert_eq!(tokens[5].lexeme, "\"a b\"");
+    }
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("café = \"né\" + x".to_string());
+        assert!(scanner.error().is_none());
+
+        let tokens = scanner.tokens();
+        assert_eq!((tokens[0].start_col, tokens[0].col), (1, 4));
+        assert_eq!(tokens[1].col, 6);
+        assert_eq!((tokens[2].start_col, tokens[2].col), (8, 11));
+        assert_eq!(tokens[4].col, 15);
+
+        // byte offsets still point into the source text
+        assert_eq!((tokens[0].start_byte, tokens[0].end_byte), (0, 5));
+    }
+
+    #[test]
+    fn unknown_multi_byte_characters_are_reported_whole() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("x € y".to_string());
+
+        assert!(scanner.error().unwrap().info.contains('€'));
+        assert_eq!(scanner.tokens().len(), 3);
+    }
+
+    #[test]
+    fn unterminated_string_is_an_error() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("var a = \"abc".to_string());
+
+        let error = scanner.error().unwrap();
+        assert_eq!(error.info, "Unterminated string found");
+        assert_eq!(error.line_text, "var a = \"abc");
+    }
+
+    #[test]
+    fn multi_line_string_keeps_line_and_col() {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens("\"a\nbc\" x".to_string());
+
+        let x = &scanner.tokens()[1];
+        assert_eq!(x.line, 2);
+        assert_eq!(x.col, 5);
+    }
+}
@@ -0,0 +1,545 @@
+/*
+    serialize.rs: A binary format for chunks, so a script can be compiled
+    once and run later.
+*/
+
+use std::rc::Rc;
+
+use crate::bytecode::{
+    Chunk,
+    Constant,
+    Function,
+    Op,
+    Upvalue,
+};
+
+
+const MAGIC: &[u8; 4] = b"LOXC";
+// bump whenever the layout below changes, old files are then rejected
+const VERSION: u8 = 2;
+
+const CONSTANT_NUMBER: u8 = 0;
+const CONSTANT_STRING: u8 = 1;
+const CONSTANT_FUNCTION: u8 = 2;
+const CONSTANT_BOOL: u8 = 3;
+const CONSTANT_NIL: u8 = 4;
+
+// the most locals a function can have, as the compiler enforces
+const MAX_LOCALS: usize = 256;
+
+/// Why a byte string isn't a chunk we can load
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub message: String,
+    // where in the input decoding gave up
+    pub offset: usize,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at byte {})", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Chunk {
+    /// Encode the ops, the line table and the constants, functions
+    /// included. Spans and columns are left out, they only serve error
+    /// messages while the source is at hand.
+    ///
+    /// All numbers are little endian, operands are `u32` except those of
+    /// `Constant`, `BuildList`, `Call` and `TailCall` which take one byte.
+    /// A `Constant` past that byte is written as a `ConstantLong`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_chunk(&mut bytes, self);
+        bytes
+    }
+
+    /// The inverse of `to_bytes`. Every operand is checked against the
+    /// chunk it is in, so a damaged file is an error here rather than a
+    /// panic in the VM. Without spans, runtime errors in the result show
+    /// the line that failed but no caret under it
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, DecodeError> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(reader.error_at(0, "Not a compiled chunk."));
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(reader.error_at(
+                MAGIC.len(),
+                &format!("Unsupported chunk version {}, expected {}.", version, VERSION),
+            ));
+        }
+
+        // the script has no upvalues to capture
+        let chunk = reader.chunk(0)?;
+        if reader.pos != bytes.len() {
+            return Err(reader.error("Trailing bytes after the chunk."));
+        }
+        Ok(chunk)
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("value too large to serialize");
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, chunk: &Chunk) {
+    write_u32(bytes, chunk.code.len());
+    for op in chunk.code.iter() {
+        write_op(bytes, op);
+    }
+
+    write_u32(bytes, chunk.lines.len());
+    for (first, line) in chunk.lines.iter() {
+        write_u32(bytes, *first);
+        write_u32(bytes, *line);
+    }
+
+    write_u32(bytes, chunk.constants.len());
+    for constant in chunk.constants.iter() {
+        match constant {
+            Constant::Number(num) => {
+                bytes.push(CONSTANT_NUMBER);
+                bytes.extend_from_slice(&num.to_bits().to_le_bytes());
+            },
+            Constant::String(string) => {
+                bytes.push(CONSTANT_STRING);
+                write_str(bytes, string);
+            },
+            Constant::Function(function) => {
+                bytes.push(CONSTANT_FUNCTION);
+                bytes.push(function.arity);
+                write_str(bytes, &function.name);
+                write_u32(bytes, function.upvalues.len());
+                for upvalue in function.upvalues.iter() {
+                    bytes.push(upvalue.is_local as u8);
+                    write_u32(bytes, upvalue.index);
+                }
+                write_chunk(bytes, &function.chunk);
+            },
+            Constant::Bool(value) => bytes.extend_from_slice(&[CONSTANT_BOOL, *value as u8]),
+            Constant::Nil => bytes.push(CONSTANT_NIL),
+        }
+    }
+}
+
+// what follows the tag of an op
+enum Operand {
+    None,
+    Byte(u8),
+    Word(usize),
+}
+
+/// The tag byte of every op, followed by its operand if it has one
+fn write_op(bytes: &mut Vec<u8>, op: &Op) {
+    let (tag, operand) = match *op {
+        Op::Return  => (0, Operand::None),
+        Op::Constant(idx) if idx <= u8::MAX as usize => (1, Operand::Byte(idx as u8)),
+        Op::Constant(idx) | Op::ConstantLong(idx) => (2, Operand::Word(idx)),
+        Op::Nil     => (3, Operand::None),
+        Op::True    => (4, Operand::None),
+        Op::False   => (5, Operand::None),
+        Op::Negate  => (6, Operand::None),
+        Op::Add     => (7, Operand::None),
+        Op::Subtract    => (8, Operand::None),
+        Op::Multiply    => (9, Operand::None),
+        Op::Divide  => (10, Operand::None),
+        Op::Modulo  => (11, Operand::None),
+        Op::BitAnd  => (12, Operand::None),
+        Op::BitOr   => (13, Operand::None),
+        Op::BitXor  => (14, Operand::None),
+        Op::BitNot  => (15, Operand::None),
+        Op::ShiftLeft   => (16, Operand::None),
+        Op::ShiftRight  => (17, Operand::None),
+        Op::Not     => (18, Operand::None),
+        Op::Equal   => (19, Operand::None),
+        Op::Greater => (20, Operand::None),
+        Op::Less    => (21, Operand::None),
+        Op::Print   => (22, Operand::None),
+        Op::Pop     => (23, Operand::None),
+        Op::DefineGlobal(idx)   => (24, Operand::Word(idx)),
+        Op::GetGlobal(idx)  => (25, Operand::Word(idx)),
+        Op::SetGlobal(idx)  => (26, Operand::Word(idx)),
+        Op::DelGlobal(idx)  => (27, Operand::Word(idx)),
+        Op::GetGlobalCached(slot)   => (28, Operand::Word(slot)),
+        Op::SetGlobalCached(slot)   => (29, Operand::Word(slot)),
+        Op::GetLocal(slot)  => (30, Operand::Word(slot)),
+        Op::SetLocal(slot)  => (31, Operand::Word(slot)),
+        Op::GetUpvalue(idx) => (32, Operand::Word(idx)),
+        Op::SetUpvalue(idx) => (33, Operand::Word(idx)),
+        Op::Closure(idx)    => (34, Operand::Word(idx)),
+        Op::CloseUpvalue    => (35, Operand::None),
+        Op::Class(idx)  => (36, Operand::Word(idx)),
+        Op::Method(idx) => (37, Operand::Word(idx)),
+        Op::Inherit     => (38, Operand::None),
+        Op::GetSuper(idx)   => (39, Operand::Word(idx)),
+        Op::BuildList(count)    => (40, Operand::Byte(count)),
+        Op::Index   => (41, Operand::None),
+        Op::IndexSet    => (42, Operand::None),
+        Op::Jump(target)    => (43, Operand::Word(target)),
+        Op::JumpIfFalse(target) => (44, Operand::Word(target)),
+        Op::Loop(target)    => (45, Operand::Word(target)),
+        Op::Call(count) => (46, Operand::Byte(count)),
+        Op::TailCall(count) => (47, Operand::Byte(count)),
+    };
+
+    bytes.push(tag);
+    match operand {
+        Operand::None => {},
+        Operand::Byte(byte) => bytes.push(byte),
+        Operand::Word(word) => write_u32(bytes, word),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> DecodeError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, offset: usize, message: &str) -> DecodeError {
+        DecodeError {
+            message: message.to_string(),
+            offset,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        match self.bytes.get(self.pos..self.pos + len) {
+            Some(taken) => {
+                self.pos += len;
+                Ok(taken)
+            },
+            None => Err(self.error("Unexpected end of input.")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, DecodeError> {
+        let taken = self.take(4)?;
+        Ok(u32::from_le_bytes([taken[0], taken[1], taken[2], taken[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u32()?;
+        let start = self.pos;
+        let taken = self.take(len)?;
+
+        match std::str::from_utf8(taken) {
+            Ok(string) => Ok(string.to_string()),
+            Err(_) => Err(self.error_at(start, "String constant is not valid UTF-8.")),
+        }
+    }
+
+    /// A chunk of a function with `upvalue_count` upvalues
+    fn chunk(&mut self, upvalue_count: usize) -> Result<Chunk, DecodeError> {
+        let mut chunk = Chunk::default();
+
+        // where each op starts, to point errors found later at it
+        let mut op_offsets = Vec::new();
+        let op_count = self.u32()?;
+        for _ in 0..op_count {
+            op_offsets.push(self.pos);
+            let op = self.op()?;
+            chunk.code.push(op);
+        }
+
+        let run_count = self.u32()?;
+        for _ in 0..run_count {
+            let first = self.u32()?;
+            let line = self.u32()?;
+            chunk.lines.push((first, line));
+        }
+
+        let constant_count = self.u32()?;
+        for _ in 0..constant_count {
+            let start = self.pos;
+            let constant = match self.u8()? {
+                CONSTANT_NUMBER => Constant::Number(self.f64()?),
+                CONSTANT_STRING => Constant::String(self.string()?),
+                CONSTANT_FUNCTION => Constant::Function(Rc::new(self.function(upvalue_count)?)),
+                CONSTANT_BOOL => Constant::Bool(self.u8()? != 0),
+                CONSTANT_NIL => Constant::Nil,
+                tag => return Err(self.error_at(start, &format!("Unknown constant tag {}.", tag))),
+            };
+            chunk.add_constant(constant);
+        }
+
+        for (op, offset) in chunk.code.iter().zip(op_offsets) {
+            if let Err(message) = check_operand(op, &chunk, upvalue_count) {
+                return Err(self.error_at(offset, &message));
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// A function defined in a function with `enclosing_upvalues` upvalues
+    fn function(&mut self, enclosing_upvalues: usize) -> Result<Function, DecodeError> {
+        let arity = self.u8()?;
+        let name = self.string()?;
+
+        let upvalue_count = self.u32()?;
+        let mut upvalues = Vec::new();
+        for _ in 0..upvalue_count {
+            let start = self.pos;
+            let is_local = self.u8()? != 0;
+            let index = self.u32()?;
+
+            let limit = if is_local { MAX_LOCALS } else { enclosing_upvalues };
+            if index >= limit {
+                return Err(self.error_at(start, &format!("Upvalue captures {} past the end.", index)));
+            }
+            upvalues.push(Upvalue { is_local, index });
+        }
+
+        let chunk = self.chunk(upvalue_count)?;
+        Ok(Function { arity, chunk, name, upvalues })
+    }
+
+    fn op(&mut self) -> Result<Op, DecodeError> {
+        let start = self.pos;
+
+        let op = match self.u8()? {
+            0   => Op::Return,
+            1   => Op::Constant(self.u8()? as usize),
+            2   => Op::ConstantLong(self.u32()?),
+            3   => Op::Nil,
+            4   => Op::True,
+            5   => Op::False,
+            6   => Op::Negate,
+            7   => Op::Add,
+            8   => Op::Subtract,
+            9   => Op::Multiply,
+            10  => Op::Divide,
+            11  => Op::Modulo,
+            12  => Op::BitAnd,
+            13  => Op::BitOr,
+            14  => Op::BitXor,
+            15  => Op::BitNot,
+            16  => Op::ShiftLeft,
+            17  => Op::ShiftRight,
+            18  => Op::Not,
+            19  => Op::Equal,
+            20  => Op::Greater,
+            21  => Op::Less,
+            22  => Op::Print,
+            23  => Op::Pop,
+            24  => Op::DefineGlobal(self.u32()?),
+            25  => Op::GetGlobal(self.u32()?),
+            26  => Op::SetGlobal(self.u32()?),
+            27  => Op::DelGlobal(self.u32()?),
+            28  => Op::GetGlobalCached(self.u32()?),
+            29  => Op::SetGlobalCached(self.u32()?),
+            30  => Op::GetLocal(self.u32()?),
+            31  => Op::SetLocal(self.u32()?),
+            32  => Op::GetUpvalue(self.u32()?),
+            33  => Op::SetUpvalue(self.u32()?),
+            34  => Op::Closure(self.u32()?),
+            35  => Op::CloseUpvalue,
+            36  => Op::Class(self.u32()?),
+            37  => Op::Method(self.u32()?),
+            38  => Op::Inherit,
+            39  => Op::GetSuper(self.u32()?),
+            40  => Op::BuildList(self.u8()?),
+            41  => Op::Index,
+            42  => Op::IndexSet,
+            43  => Op::Jump(self.u32()?),
+            44  => Op::JumpIfFalse(self.u32()?),
+            45  => Op::Loop(self.u32()?),
+            46  => Op::Call(self.u8()?),
+            47  => Op::TailCall(self.u8()?),
+            tag => return Err(self.error_at(start, &format!("Unknown op tag {}.", tag))),
+        };
+        Ok(op)
+    }
+}
+
+/// Why `op` can't run in `chunk`, if it can't. Only operands are checked,
+/// not whether the ops keep the stack balanced
+fn check_operand(op: &Op, chunk: &Chunk, upvalue_count: usize) -> Result<(), String> {
+    let constant = |idx: usize| chunk.constants.get(idx);
+
+    match *op {
+        Op::Constant(idx) | Op::ConstantLong(idx) => match constant(idx) {
+            Some(Constant::Function(_)) => Err(format!("Constant {} is a function, not a value.", idx)),
+            Some(_) => Ok(()),
+            None => Err(format!("Constant {} is past the end.", idx)),
+        },
+        Op::DefineGlobal(idx) | Op::GetGlobal(idx) | Op::SetGlobal(idx) | Op::DelGlobal(idx)
+        | Op::Class(idx) | Op::Method(idx) | Op::GetSuper(idx) => match constant(idx) {
+            Some(Constant::String(_)) => Ok(()),
+            Some(_) => Err(format!("Constant {} is not a name.", idx)),
+            None => Err(format!("Constant {} is past the end.", idx)),
+        },
+        Op::Closure(idx) => match constant(idx) {
+            Some(Constant::Function(_)) => Ok(()),
+            Some(_) => Err(format!("Constant {} is not a function.", idx)),
+            None => Err(format!("Constant {} is past the end.", idx)),
+        },
+        // slots are handed out by the VM that linked the chunk, a fresh
+        // one has none of them
+        Op::GetGlobalCached(_) | Op::SetGlobalCached(_) => {
+            Err("Linked global slots can't be loaded.".to_string())
+        },
+        Op::GetLocal(slot) | Op::SetLocal(slot) if slot >= MAX_LOCALS => {
+            Err(format!("Local slot {} is past the end.", slot))
+        },
+        Op::GetUpvalue(idx) | Op::SetUpvalue(idx) if idx >= upvalue_count => {
+            Err(format!("Upvalue {} is past the end.", idx))
+        },
+        Op::Jump(target) | Op::JumpIfFalse(target) | Op::Loop(target) if target >= chunk.code.len() => {
+            Err(format!("Jump to {} is past the end.", target))
+        },
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::LineNo;
+    use crate::compiler::compile;
+    use crate::scanner::Scanner;
+
+    fn compile_source(source: &str) -> Function {
+        let tokens = Scanner::new().scan(source.to_string()).unwrap();
+        compile(tokens).unwrap()
+    }
+
+    fn assert_same_chunk(a: &Chunk, b: &Chunk) {
+        assert_eq!(a.code, b.code);
+        assert_eq!(a.lines, b.lines);
+
+        assert_eq!(a.constants.len(), b.constants.len());
+        for (const_a, const_b) in a.constants.iter().zip(b.constants.iter()) {
+            match (const_a, const_b) {
+                (Constant::Number(x), Constant::Number(y)) => assert_eq!(x.to_bits(), y.to_bits()),
+                (Constant::String(x), Constant::String(y)) => assert_eq!(x, y),
+                (Constant::Bool(x), Constant::Bool(y)) => assert_eq!(x, y),
+                (Constant::Nil, Constant::Nil) => {},
+                (Constant::Function(x), Constant::Function(y)) => {
+                    assert_eq!(x.name, y.name);
+                    assert_eq!(x.arity, y.arity);
+                    assert_eq!(x.upvalues, y.upvalues);
+                    assert_same_chunk(&x.chunk, &y.chunk);
+                },
+                _ => panic!("constants differ: {} and {}", const_a, const_b),
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip_through_bytes() {
+        let source = "
+            var greeting = \"héllo\";
+            fun counter(start) {
+                var n = start;
+                fun next() { n = n + 1; return n; }
+                return next;
+            }
+            var c = counter(0.5);
+            while (c() < 10) { print [greeting, -1]; }
+        ";
+        let script = compile_source(source);
+
+        let decoded = Chunk::from_bytes(&script.chunk.to_bytes()).unwrap();
+        assert_same_chunk(&script.chunk, &decoded);
+    }
+
+    #[test]
+    fn constants_past_a_byte_round_trip() {
+        let source: String = (0..300).map(|n| format!("{};", n)).collect();
+        let script = compile_source(&source);
+
+        let decoded = Chunk::from_bytes(&script.chunk.to_bytes()).unwrap();
+        assert_same_chunk(&script.chunk, &decoded);
+    }
+
+    #[test]
+    fn stale_and_broken_input_is_rejected() {
+        let mut bytes = compile_source("print 1;").chunk.to_bytes();
+
+        let error = Chunk::from_bytes(b"nope").unwrap_err();
+        assert_eq!(error.message, "Not a compiled chunk.");
+
+        bytes[MAGIC.len()] = VERSION + 1;
+        let error = Chunk::from_bytes(&bytes).unwrap_err();
+        assert_eq!(error.offset, MAGIC.len());
+        assert!(error.message.starts_with("Unsupported chunk version"));
+
+        bytes[MAGIC.len()] = VERSION;
+        let error = Chunk::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(error.message, "Unexpected end of input.");
+    }
+
+    #[test]
+    fn operands_past_the_end_are_rejected() {
+        let cases = [
+            (Op::Constant(1), "Constant 1 is past the end."),
+            (Op::GetGlobal(0), "Constant 0 is not a name."),
+            (Op::Closure(0), "Constant 0 is not a function."),
+            (Op::GetGlobalCached(0), "Linked global slots can't be loaded."),
+            (Op::GetLocal(MAX_LOCALS), "Local slot 256 is past the end."),
+            (Op::GetUpvalue(0), "Upvalue 0 is past the end."),
+            (Op::Jump(2), "Jump to 2 is past the end."),
+        ];
+        for (op, message) in cases {
+            let mut chunk = Chunk::default();
+            chunk.add_constant(Constant::Number(1.0));
+            chunk.write(op.clone(), LineNo::new(1));
+            chunk.write(Op::Return, LineNo::new(1));
+
+            let error = Chunk::from_bytes(&chunk.to_bytes()).unwrap_err();
+            assert_eq!(error.message, message, "{:?}", op);
+            // pointing at the op, just past the header and the op count
+            assert_eq!(error.offset, MAGIC.len() + 1 + 4);
+        }
+
+        // the same goes for the chunks of functions
+        let mut body = Chunk::default();
+        body.write(Op::Constant(9), LineNo::new(1));
+        let mut chunk = Chunk::default();
+        chunk.add_constant(Constant::Function(Rc::new(Function { chunk: body, ..Default::default() })));
+
+        let error = Chunk::from_bytes(&chunk.to_bytes()).unwrap_err();
+        assert_eq!(error.message, "Constant 9 is past the end.");
+    }
+
+    #[test]
+    fn decoded_chunks_run_without_spans() {
+        let script = compile_source("print -nil;");
+        let decoded = Chunk::from_bytes(&script.chunk.to_bytes()).unwrap();
+        assert!(decoded.spans.is_empty());
+
+        let function = Function { chunk: decoded, ..Default::default() };
+        let error = crate::vm::VM::new().interpret_with_source(function, "print -nil;").unwrap_err();
+        assert_eq!(error.line_text.as_deref(), Some("print -nil;"));
+        assert_eq!(error.to_string(), "Operand must be a number.\nprint -nil;\n[line 1] in script");
+    }
+}
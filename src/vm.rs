@@ -0,0 +1,1405 @@
+/*
+    vm.rs: The stack based virtual machine that runs the compiled `Function`s.
+*/
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::bytecode::{
+    Chunk,
+    Constant,
+    Function,
+    Op,
+};
+
+
+/// A value living on the VM's stack at runtime
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Rc<RefCell<Vec<Value>>>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    BoundMethod(Rc<BoundMethod>),
+}
+
+/// How a value reads when printed. Numbers drop the `.0` when integral
+/// and strings print without quotes. `Constant`'s `Display` goes through
+/// here too so the disassembly shows numbers the way `print` does
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::Nil          => "nil".to_string(),
+        Value::Bool(value)  => value.to_string(),
+        // f64's Display already drops the `.0` of integral numbers
+        Value::Number(num)  => num.to_string(),
+        Value::String(string)   => string.clone(),
+        Value::List(list)   => {
+            let items: Vec<String> = list
+                .borrow()
+                .iter()
+                .map(format_value)
+                .collect();
+            format!("[{}]", items.join(", "))
+        },
+        Value::Closure(closure) => closure.function.to_string(),
+        Value::Class(class) => class.name.clone(),
+        Value::BoundMethod(bound) => format_value(&Value::Closure(bound.method.clone())),
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_value(self))
+    }
+}
+
+impl Value {
+    /// What kind of value this is, for runtime error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil          => "nil",
+            Value::Bool(_)      => "bool",
+            Value::Number(_)    => "number",
+            Value::String(_)    => "string",
+            Value::List(_)      => "list",
+            Value::Closure(_)   => "function",
+            Value::Class(_)     => "class",
+            Value::BoundMethod(_)   => "method",
+        }
+    }
+}
+
+impl From<&Constant> for Value {
+    fn from(constant: &Constant) -> Self {
+        match constant {
+            Constant::Number(num)   => Value::Number(*num),
+            Constant::String(string)    => Value::String(string.clone()),
+            Constant::Function(function)    => Value::Closure(Rc::new(Closure {
+                function: function.clone(),
+                upvalues: Vec::new(),
+            })),
+            Constant::Bool(value)   => Value::Bool(*value),
+            Constant::Nil   => Value::Nil,
+        }
+    }
+}
+
+/// A captured variable. It points into the stack while the variable is
+/// still alive there and owns the value once that slot is popped
+#[derive(Debug)]
+pub enum UpvalueState {
+    Open(usize),
+    Closed(Value),
+}
+
+type UpvalueRef = Rc<RefCell<UpvalueState>>;
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    upvalues: Vec<UpvalueRef>,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String, Rc<Closure>>>,
+}
+
+/// A method together with the receiver `this` refers to while it runs
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+
+    // the source line that failed and the column the failing expression
+    // starts at in it, when the VM was given the source. The column counts
+    // characters, a tab is one, and is 0 when the chunk doesn't know where
+    // its ops came from
+    pub line_text: Option<String>,
+    pub col: usize,
+
+    // one `[line N] in ...` entry per frame, innermost first
+    pub trace: Vec<String>,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(line_text) = &self.line_text {
+            write!(f, "\n{}", line_text)?;
+            if self.col > 0 {
+                // keep tabs so the caret lines up however they are shown
+                let indent: String = line_text
+                    .chars()
+                    .take(self.col - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(f, "\n{}^", indent)?;
+            }
+        }
+
+        if self.trace.is_empty() {
+            return write!(f, "\n[line {}] in script", self.line);
+        }
+        for entry in &self.trace {
+            write!(f, "\n{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// Deepest call nesting before we report a stack overflow
+const FRAMES_MAX: usize = 64;
+
+struct CallFrame {
+    closure: Rc<Closure>,
+
+    // index of the next op to run
+    ip: usize,
+
+    // where the frame's slot 0 is on the value stack
+    slot_base: usize,
+}
+
+/// A global variable. `value` is `None` until it is defined and again
+/// after it is deleted, the slot itself is never reused
+#[derive(Debug)]
+struct Global {
+    name: String,
+    value: Option<Value>,
+}
+
+#[derive(Default)]
+pub struct VM {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+
+    // every global ever named by a script run here, and its index there
+    globals: Vec<Global>,
+    global_slots: HashMap<String, usize>,
+
+    // sorted by stack slot, so closing them is a walk from the end
+    open_upvalues: Vec<UpvalueRef>,
+
+    // where `print` writes to, stdout when unset
+    output: Option<Box<dyn Write>>,
+
+    // the source being run, for showing where an error happened
+    source: String,
+}
+
+impl VM {
+    pub fn new() -> Self {
+        VM::default()
+    }
+
+    /// Send everything `print` writes to `output` instead of stdout
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    /// Run a compiled script and hand back whatever it returned
+    pub fn interpret(&mut self, script: Function) -> Result<Value, RuntimeError> {
+        self.interpret_with_source(script, "")
+    }
+
+    /// Like `interpret`, given the source `script` was compiled from so
+    /// runtime errors can show the line that failed
+    pub fn interpret_with_source(
+        &mut self,
+        mut script: Function,
+        source: &str,
+    ) -> Result<Value, RuntimeError> {
+        self.source = source.to_string();
+        self.link(&mut script.chunk);
+        let closure = Rc::new(Closure {
+            function: Rc::new(script),
+            upvalues: Vec::new(),
+        });
+
+        self.stack.push(Value::Closure(closure.clone()));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base: 0,
+        });
+
+        let result = self.run();
+        if result.is_err() {
+            // don't leave a half run program behind
+            self.reset();
+        }
+        result
+    }
+
+    /// Drop everything left over from the last run but keep the globals,
+    /// and the memory already allocated for the stacks, for the next one
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    /// Forget every global defined so far
+    pub fn clear_globals(&mut self) {
+        // the slots stay, code linked against them may still run
+        for global in &mut self.globals {
+            global.value = None;
+        }
+    }
+
+    /// The current value of the global `name`, if it is defined
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        let slot = *self.global_slots.get(name)?;
+        self.globals[slot].value.as_ref()
+    }
+
+    /// Resolve every global read and write in `chunk`, and in the
+    /// functions defined in it, to its slot once up front, so running them
+    /// indexes a `Vec` instead of hashing the name each time. A slot keeps
+    /// its name forever, so redefining or deleting the global can't leave
+    /// a stale slot behind
+    fn link(&mut self, chunk: &mut Chunk) {
+        for constant in chunk.constants.iter_mut() {
+            if let Constant::Function(function) = constant {
+                self.link(&mut Rc::make_mut(function).chunk);
+            }
+        }
+
+        for idx in 0..chunk.code.len() {
+            let linked = match chunk.code[idx] {
+                Op::GetGlobal(name_idx) => Op::GetGlobalCached(self.slot_of(chunk, name_idx)),
+                Op::SetGlobal(name_idx) => Op::SetGlobalCached(self.slot_of(chunk, name_idx)),
+                _ => continue,
+            };
+            chunk.code[idx] = linked;
+        }
+    }
+
+    /// The slot of the global named by constant `name_idx`, made on demand
+    fn slot_of(&mut self, chunk: &Chunk, name_idx: usize) -> usize {
+        let name = match &chunk.constants[name_idx] {
+            Constant::String(name) => name.clone(),
+            other => other.to_string(),
+        };
+        self.global_slot(name)
+    }
+
+    fn global_slot(&mut self, name: String) -> usize {
+        if let Some(slot) = self.global_slots.get(&name) {
+            return *slot;
+        }
+
+        let slot = self.globals.len();
+        self.global_slots.insert(name.clone(), slot);
+        self.globals.push(Global { name, value: None });
+        slot
+    }
+
+    fn undefined_global(&self, slot: usize) -> RuntimeError {
+        self.runtime_error(&format!("Undefined variable '{}'.", self.globals[slot].name))
+    }
+
+    fn run(&mut self) -> Result<Value, RuntimeError> {
+        loop {
+            let op = {
+                let frame = self.frame_mut();
+                let op = frame.closure.function.chunk.code[frame.ip].clone();
+                frame.ip += 1;
+                op
+            };
+
+            match op {
+                Op::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("no frame to return from");
+                    self.close_upvalues(frame.slot_base);
+
+                    self.stack.truncate(frame.slot_base);
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.push(result);
+                },
+                Op::Constant(idx) | Op::ConstantLong(idx) => {
+                    let value = Value::from(&self.frame().closure.function.chunk.constants[idx]);
+                    self.push(value);
+                },
+                Op::Nil     => self.push(Value::Nil),
+                Op::True    => self.push(Value::Bool(true)),
+                Op::False   => self.push(Value::Bool(false)),
+                Op::Negate  => {
+                    let num = match self.pop() {
+                        Value::Number(num) => num,
+                        _ => return Err(self.runtime_error("Operand must be a number.")),
+                    };
+                    self.push(Value::Number(-num));
+                },
+                Op::Add         => self.add()?,
+                Op::Subtract    => self.binary_number_op(|a, b| Value::Number(a - b))?,
+                Op::Multiply    => self.binary_number_op(|a, b| Value::Number(a * b))?,
+                Op::Divide      => self.binary_number_op(|a, b| Value::Number(a / b))?,
+                Op::Modulo => {
+                    if let Value::Number(divisor) = self.peek(0) {
+                        if *divisor == 0.0 {
+                            return Err(self.runtime_error("Modulo by zero."));
+                        }
+                    }
+                    self.binary_number_op(|a, b| Value::Number(a % b))?
+                },
+                Op::BitAnd  => self.binary_integer_op(|a, b| Some(a & b))?,
+                Op::BitOr   => self.binary_integer_op(|a, b| Some(a | b))?,
+                Op::BitXor  => self.binary_integer_op(|a, b| Some(a ^ b))?,
+                Op::ShiftLeft   => self.binary_integer_op(|a, b| a.checked_shl(u32::try_from(b).ok()?))?,
+                Op::ShiftRight  => self.binary_integer_op(|a, b| a.checked_shr(u32::try_from(b).ok()?))?,
+                Op::BitNot  => {
+                    let value = self.pop();
+                    let int = self.as_integer(&value, "Operand must be an integer.")?;
+                    self.push(Value::Number(!int as f64));
+                },
+                Op::Greater     => self.binary_number_op(|a, b| Value::Bool(a > b))?,
+                Op::Less        => self.binary_number_op(|a, b| Value::Bool(a < b))?,
+                Op::Not => {
+                    let value = self.pop();
+                    self.push(Value::Bool(VM::is_falsey(&value)));
+                },
+                Op::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(VM::values_equal(&a, &b)));
+                },
+                Op::Print => {
+                    let value = self.pop();
+                    let written = match &mut self.output {
+                        Some(output) => writeln!(output, "{}", value),
+                        None => writeln!(std::io::stdout(), "{}", value),
+                    };
+                    if written.is_err() {
+                        return Err(self.runtime_error("Could not write output."));
+                    }
+                },
+                Op::Pop => {
+                    self.pop();
+                },
+                Op::DefineGlobal(name_idx) => {
+                    let name = self.read_name(name_idx);
+                    let slot = self.global_slot(name);
+                    self.globals[slot].value = Some(self.pop());
+                },
+                Op::GetGlobal(name_idx) => {
+                    let slot = self.global_slot(self.read_name(name_idx));
+                    self.get_global(slot)?;
+                },
+                Op::GetGlobalCached(slot) => self.get_global(slot)?,
+                Op::DelGlobal(name_idx) => {
+                    let slot = self.global_slot(self.read_name(name_idx));
+                    if self.globals[slot].value.take().is_none() {
+                        return Err(self.undefined_global(slot));
+                    }
+                },
+                Op::SetGlobal(name_idx) => {
+                    let slot = self.global_slot(self.read_name(name_idx));
+                    self.set_global(slot)?;
+                },
+                Op::SetGlobalCached(slot) => self.set_global(slot)?,
+                Op::GetLocal(slot) => {
+                    let value = self.stack[self.frame().slot_base + slot].clone();
+                    self.push(value);
+                },
+                Op::SetLocal(slot) => {
+                    let idx = self.frame().slot_base + slot;
+                    self.stack[idx] = self.peek(0).clone();
+                },
+                Op::GetUpvalue(idx) => {
+                    let upvalue = self.frame().closure.upvalues[idx].clone();
+                    let value = match &*upvalue.borrow() {
+                        UpvalueState::Open(slot)    => self.stack[*slot].clone(),
+                        UpvalueState::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
+                },
+                Op::SetUpvalue(idx) => {
+                    let upvalue = self.frame().closure.upvalues[idx].clone();
+                    let value = self.peek(0).clone();
+                    let mut state = upvalue.borrow_mut();
+                    match &mut *state {
+                        UpvalueState::Open(slot)    => self.stack[*slot] = value,
+                        UpvalueState::Closed(closed)    => *closed = value,
+                    }
+                },
+                Op::Closure(function_idx) => {
+                    let function = match &self.frame().closure.function.chunk.constants[function_idx] {
+                        Constant::Function(function) => function.clone(),
+                        _ => return Err(self.runtime_error("Closure operand is not a function.")),
+                    };
+
+                    let slot_base = self.frame().slot_base;
+                    let upvalues = function
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            if upvalue.is_local {
+                                self.capture_upvalue(slot_base + upvalue.index)
+                            } else {
+                                self.frame().closure.upvalues[upvalue.index].clone()
+                            }
+                        })
+                        .collect();
+
+                    self.push(Value::Closure(Rc::new(Closure {
+                        function,
+                        upvalues,
+                    })));
+                },
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                },
+                Op::Class(name_idx) => {
+                    let name = self.read_name(name_idx);
+                    self.push(Value::Class(Rc::new(Class {
+                        name,
+                        methods: RefCell::new(HashMap::new()),
+                    })));
+                },
+                Op::Method(name_idx) => {
+                    let name = self.read_name(name_idx);
+                    let method = match self.pop() {
+                        Value::Closure(closure) => closure,
+                        other => unreachable!("OP_METHOD on {:?}", other),
+                    };
+                    match self.peek(0) {
+                        Value::Class(class) => {
+                            class.methods.borrow_mut().insert(name, method);
+                        },
+                        other => unreachable!("OP_METHOD bound to {:?}", other),
+                    }
+                },
+                Op::Inherit => {
+                    let methods = match (self.peek(1), self.peek(0)) {
+                        (Value::Class(superclass), Value::Class(_)) => superclass.methods.borrow().clone(),
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    if let Value::Class(subclass) = self.pop() {
+                        subclass.methods.borrow_mut().extend(methods);
+                    }
+                },
+                Op::GetSuper(name_idx) => {
+                    let name = self.read_name(name_idx);
+                    let method = match self.pop() {
+                        Value::Class(superclass) => superclass.methods.borrow().get(&name).cloned(),
+                        other => unreachable!("OP_GET_SUPER on {:?}", other),
+                    };
+                    let Some(method) = method else {
+                        return Err(self.runtime_error(&format!("Undefined property '{}'.", name)));
+                    };
+
+                    let receiver = self.pop();
+                    self.push(Value::BoundMethod(Rc::new(BoundMethod { receiver, method })));
+                },
+                Op::BuildList(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count as usize);
+                    self.push(Value::List(Rc::new(RefCell::new(items))));
+                },
+                Op::Index => {
+                    let index = self.pop();
+                    let collection = self.pop();
+                    let value = self.index(&collection, &index)?;
+                    self.push(value);
+                },
+                Op::IndexSet => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let collection = self.pop();
+                    self.index_set(&collection, &index, value.clone())?;
+                    self.push(value);
+                },
+                Op::Jump(target) => {
+                    self.frame_mut().ip = target;
+                },
+                Op::JumpIfFalse(target) => {
+                    if VM::is_falsey(self.peek(0)) {
+                        self.frame_mut().ip = target;
+                    }
+                },
+                Op::Loop(target) => {
+                    self.frame_mut().ip = target;
+                },
+                Op::Call(arg_count) => {
+                    let closure = self.callee(arg_count as usize)?;
+                    if self.frames.len() == FRAMES_MAX {
+                        return Err(self.runtime_error("Stack overflow."));
+                    }
+
+                    self.frames.push(CallFrame {
+                        closure,
+                        ip: 0,
+                        slot_base: self.stack.len() - arg_count as usize - 1,
+                    });
+                },
+                Op::TailCall(arg_count) => {
+                    let closure = self.callee(arg_count as usize)?;
+                    let frame = self.frames.pop().expect("no frame to replace");
+                    self.close_upvalues(frame.slot_base);
+
+                    // slide the callee and its arguments down over our frame
+                    let callee_idx = self.stack.len() - arg_count as usize - 1;
+                    let moved = self.stack.split_off(callee_idx);
+                    self.stack.truncate(frame.slot_base);
+                    self.stack.extend(moved);
+
+                    self.frames.push(CallFrame {
+                        closure,
+                        ip: 0,
+                        slot_base: frame.slot_base,
+                    });
+                },
+            }
+        }
+    }
+
+    fn get_global(&mut self, slot: usize) -> Result<(), RuntimeError> {
+        match &self.globals[slot].value {
+            Some(value) => {
+                let value = value.clone();
+                self.push(value);
+                Ok(())
+            },
+            None => Err(self.undefined_global(slot)),
+        }
+    }
+
+    fn set_global(&mut self, slot: usize) -> Result<(), RuntimeError> {
+        if self.globals[slot].value.is_none() {
+            return Err(self.undefined_global(slot));
+        }
+        // assignment is an expression, the value stays on the stack
+        self.globals[slot].value = Some(self.peek(0).clone());
+        Ok(())
+    }
+
+    /// The closure about to be called with `arg_count` arguments, after
+    /// checking it can be called with that many
+    fn callee(&mut self, arg_count: usize) -> Result<Rc<Closure>, RuntimeError> {
+        let closure = match self.peek(arg_count) {
+            Value::Closure(closure) => closure.clone(),
+            Value::BoundMethod(bound) => {
+                // the receiver takes the callee's slot, where `this` lives
+                let bound = bound.clone();
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = bound.receiver.clone();
+                bound.method.clone()
+            },
+            _ => return Err(self.runtime_error("Can only call functions.")),
+        };
+
+        if arg_count != closure.function.arity as usize {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                closure.function.arity,
+                arg_count
+            )));
+        }
+        Ok(closure)
+    }
+
+    /// `nil` and `false` are falsey, everything else is truthy
+    fn is_falsey(value: &Value) -> bool {
+        matches!(value, Value::Nil | Value::Bool(false))
+    }
+
+    fn values_equal(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Nil, Value::Nil)    => true,
+            (Value::Bool(a), Value::Bool(b))    => a == b,
+            (Value::Number(a), Value::Number(b))    => a == b,
+            (Value::String(a), Value::String(b))    => a == b,
+            // lists, closures and classes are compared by identity
+            (Value::List(a), Value::List(b))    => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b))  => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b))  => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b))  => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// `+` adds two numbers or concatenates two strings, like Lox
+    fn add(&mut self) -> Result<(), RuntimeError> {
+        let result = match (self.peek(1), self.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+            (Value::String(a), Value::String(b)) => Value::String(format!("{}{}", a, b)),
+            (a, b) => return Err(self.runtime_error(&format!(
+                "Operands must be two numbers or two strings, got {} and {}.",
+                a.type_name(),
+                b.type_name()
+            ))),
+        };
+
+        self.pop();
+        self.pop();
+        self.push(result);
+        Ok(())
+    }
+
+    fn binary_number_op(&mut self, op: fn(f64, f64) -> Value) -> Result<(), RuntimeError> {
+        let (a, b) = match (self.peek(1), self.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => (*a, *b),
+            _ => return Err(self.runtime_error("Operands must be numbers.")),
+        };
+
+        self.pop();
+        self.pop();
+        self.push(op(a, b));
+        Ok(())
+    }
+
+    /// Numbers with no fractional part, as the `i64` bitwise ops work on
+    fn as_integer(&self, value: &Value, message: &str) -> Result<i64, RuntimeError> {
+        match value {
+            Value::Number(num) if num.fract() == 0.0 && num.abs() <= i64::MAX as f64 => Ok(*num as i64),
+            _ => Err(self.runtime_error(message)),
+        }
+    }
+
+    /// Apply a bitwise op to the two integers on top of the stack. `op`
+    /// returns None when the result doesn't exist, like an oversized shift
+    fn binary_integer_op(&mut self, op: fn(i64, i64) -> Option<i64>) -> Result<(), RuntimeError> {
+        let a = self.as_integer(self.peek(1), "Operands must be integers.")?;
+        let b = self.as_integer(self.peek(0), "Operands must be integers.")?;
+
+        let result = match op(a, b) {
+            Some(result) => result,
+            None => return Err(self.runtime_error("Shift amount out of range.")),
+        };
+
+        self.pop();
+        self.pop();
+        self.push(Value::Number(result as f64));
+        Ok(())
+    }
+
+    /// Turn an index value into a position inside a collection of `len`
+    fn checked_index(&self, index: &Value, len: usize) -> Result<usize, RuntimeError> {
+        let idx = match index {
+            Value::Number(num) if num.fract() == 0.0 => *num,
+            _ => return Err(self.runtime_error("Index must be an integer.")),
+        };
+
+        if idx < 0.0 || idx >= len as f64 {
+            return Err(self.runtime_error(
+                &format!("Index {} out of range for length {}.", idx, len)
+            ));
+        }
+        Ok(idx as usize)
+    }
+
+    fn index(&self, collection: &Value, index: &Value) -> Result<Value, RuntimeError> {
+        match collection {
+            Value::List(list) => {
+                let list = list.borrow();
+                let idx = self.checked_index(index, list.len())?;
+                Ok(list[idx].clone())
+            },
+            Value::String(string) => {
+                let idx = self.checked_index(index, string.chars().count())?;
+                let c = string.chars().nth(idx).expect("index was checked");
+                Ok(Value::String(c.to_string()))
+            },
+            _ => Err(self.runtime_error("Only lists and strings can be indexed.")),
+        }
+    }
+
+    fn index_set(&self, collection: &Value, index: &Value, value: Value) -> Result<(), RuntimeError> {
+        match collection {
+            Value::List(list) => {
+                let idx = self.checked_index(index, list.borrow().len())?;
+                list.borrow_mut()[idx] = value;
+                Ok(())
+            },
+            Value::String(_) => Err(self.runtime_error("Strings can't be modified.")),
+            _ => Err(self.runtime_error("Only lists can be assigned to by index.")),
+        }
+    }
+
+    /// Reuse the upvalue already pointing at `slot` if there is one, so
+    /// closures over the same variable see each others writes
+    fn capture_upvalue(&mut self, slot: usize) -> UpvalueRef {
+        for upvalue in self.open_upvalues.iter() {
+            if let UpvalueState::Open(open_slot) = *upvalue.borrow() {
+                if open_slot == slot {
+                    return upvalue.clone();
+                }
+            }
+        }
+
+        let upvalue = Rc::new(RefCell::new(UpvalueState::Open(slot)));
+        let position = self.open_upvalues.iter().position(|open| {
+            matches!(*open.borrow(), UpvalueState::Open(open_slot) if open_slot > slot)
+        });
+        match position {
+            Some(position) => self.open_upvalues.insert(position, upvalue.clone()),
+            None => self.open_upvalues.push(upvalue.clone()),
+        }
+        upvalue
+    }
+
+    /// Move every captured variable living at or above `from` off the stack
+    fn close_upvalues(&mut self, from: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                UpvalueState::Open(slot) if slot >= from => slot,
+                _ => break,
+            };
+
+            let upvalue = self.open_upvalues.pop().expect("checked above");
+            *upvalue.borrow_mut() = UpvalueState::Closed(self.stack[slot].clone());
+        }
+    }
+
+    fn read_name(&self, name_idx: usize) -> String {
+        match &self.frame().closure.function.chunk.constants[name_idx] {
+            Constant::String(name) => name.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    fn runtime_error(&self, message: &str) -> RuntimeError {
+        // ip already points past the op that failed
+        let frame_line = |frame: &CallFrame| {
+            frame.closure.function.chunk.line_at(frame.ip.saturating_sub(1)).unwrap_or(0)
+        };
+
+        let trace = self
+            .frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(depth, frame)| {
+                let function = &frame.closure.function;
+                // the bottom frame is always the script itself
+                let location = if depth == 0 {
+                    "script".to_string()
+                } else if function.name.is_empty() {
+                    "lambda()".to_string()
+                } else {
+                    format!("{}()", function.name)
+                };
+                format!("[line {}] in {}", frame_line(frame), location)
+            })
+            .collect();
+
+        let line = frame_line(self.frame());
+        let span_start = {
+            let frame = self.frame();
+            let spans = &frame.closure.function.chunk.spans;
+            spans.get(frame.ip.saturating_sub(1)).map(|(start, _)| *start)
+        };
+        let (line_text, col) = match self.source_location(line, span_start) {
+            Some((line_text, col)) => (Some(line_text), col),
+            None => (None, 0),
+        };
+
+        RuntimeError {
+            message: message.to_string(),
+            line,
+            line_text,
+            col,
+            trace,
+        }
+    }
+
+    /// The text of source line `line` and the column the byte offset
+    /// `byte` is at in it. The column is 1 when `byte` is on another line,
+    /// as it is for an expression that started further up, and 0 when there
+    /// is no `byte`, as for chunks loaded with `Chunk::from_bytes`
+    fn source_location(&self, line: usize, byte: Option<usize>) -> Option<(String, usize)> {
+        let mut line_start = 0;
+        for text in self.source.split_inclusive('\n').take(line.checked_sub(1)?) {
+            line_start += text.len();
+        }
+        let text = self.source[line_start ..].split_inclusive('\n').next()?;
+
+        let col = match byte {
+            Some(byte) if (line_start .. line_start + text.len()).contains(&byte) => {
+                self.source[line_start .. byte].chars().count() + 1
+            },
+            Some(_) => 1,
+            None => 0,
+        };
+        Some((text.trim_end_matches(['\n', '\r']).to_string(), col))
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no frame is running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no frame is running")
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::LineNo;
+    use crate::compiler::compile;
+    use crate::scanner::Scanner;
+
+    fn compile_source(source: &str) -> Function {
+        let mut scanner = Scanner::default();
+        scanner.scan_tokens(source.to_string());
+        compile(scanner.tokens().to_vec()).unwrap()
+    }
+
+    /// A `Write` that can still be read after the VM took it
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_printing(source: &str) -> String {
+        let output = SharedOutput::default();
+        let mut vm = VM::new().with_output(output.clone());
+        vm.interpret(compile_source(source)).unwrap();
+
+        let bytes = output.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn global_number(vm: &VM, name: &str) -> f64 {
+        match vm.global(name) {
+            Some(Value::Number(num)) => *num,
+            other => panic!("expected a number in '{}', got {:?}", name, other),
+        }
+    }
+
+    #[test]
+    fn interpret_returns_the_top_of_the_stack() {
+        let line = LineNo::new(1);
+        let mut script = Function::default();
+        let one = script.chunk.add_constant_number(1.0);
+        let two = script.chunk.add_constant_number(2.0);
+        for op in [Op::Constant(one), Op::Constant(two), Op::Add, Op::Negate, Op::Return] {
+            script.chunk.write(op, line);
+        }
+
+        let result = VM::new().interpret(script).unwrap();
+        assert!(matches!(result, Value::Number(num) if num == -3.0));
+    }
+
+    #[test]
+    fn adding_nil_is_a_runtime_error() {
+        let error = VM::new().interpret(compile_source("print 1;
+print 1 + nil;")).unwrap_err();
+
+        assert_eq!(error.message, "Operands must be two numbers or two strings, got number and nil.");
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn adding_strings_concatenates_them() {
+        assert_eq!(run_printing("print \"foo\" + \"bar\";"), "foobar\n");
+    }
+
+    #[test]
+    fn adding_a_number_to_a_string_is_a_runtime_error() {
+        let error = VM::new().interpret(compile_source("1 + \"x\";")).unwrap_err();
+
+        assert_eq!(error.message, "Operands must be two numbers or two strings, got number and string.");
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn only_nil_and_false_are_falsey() {
+        assert_eq!(
+            run_printing("print !nil;\nprint !false;\nprint !0;\nprint !\"x\";\nprint !\"\";"),
+            "true\ntrue\nfalse\nfalse\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn equality_compares_values_structurally() {
+        assert_eq!(
+            run_printing("print nil == nil;\nprint 1 == 1;\nprint \"a\" + \"b\" == \"ab\";\nprint nil == false;\nprint 0 == \"0\";"),
+            "true\ntrue\ntrue\nfalse\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn if_else_runs_only_the_taken_branch() {
+        assert_eq!(run_printing("if (true) print 1; else print 2;"), "1\n");
+
+        // a condition the compiler can't see through goes through the jumps
+        let source = "var yes = true;\nif (yes) print 1; else print 2;\nif (!yes) print 3; else print 4;";
+        let script = compile_source(source);
+        assert!(script.chunk.code.iter().any(|op| matches!(op, Op::JumpIfFalse(_))));
+        assert_eq!(run_printing(source), "1\n4\n");
+    }
+
+    #[test]
+    fn while_loops_until_the_condition_is_false() {
+        let source = "var n = 3;\nwhile (n > 0) {\n  print n;\n  n = n - 1;\n}\nprint \"liftoff\";";
+
+        let script = compile_source(source);
+        assert!(script.chunk.code.iter().any(|op| matches!(op, Op::Loop(_))));
+        assert_eq!(run_printing(source), "3\n2\n1\nliftoff\n");
+    }
+
+    #[test]
+    fn calls_return_their_value_to_the_caller() {
+        let mut vm = VM::new();
+        vm.interpret(compile_source("fun answer() { return 42; }\nvar a = answer() + 1;")).unwrap();
+
+        assert_eq!(global_number(&vm, "a"), 43.0);
+        // the frame is gone and its slots with it
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn calls_check_the_argument_count() {
+        let error = VM::new()
+            .interpret(compile_source("fun add(a, b) { return a + b; }\nadd(1);"))
+            .unwrap_err();
+
+        assert_eq!(error.message, "Expected 2 arguments but got 1.");
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn lambdas_can_be_called_where_they_are_made() {
+        let source = "print (lambda (a, b) -> a + b)(1, 2);\nprint (lambda () { return \"block\"; })();";
+        assert_eq!(run_printing(source), "3\nblock\n");
+
+        // nothing got a name along the way
+        let script = compile_source(source);
+        assert!(!script.chunk.code.iter().any(|op| matches!(op, Op::DefineGlobal(_))));
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        let source = "
+            fun say(value) { print value; return value; }
+            print say(false) and say(1);
+            print say(nil) or say(2);
+            print say(3) or say(4);
+            print say(5) and say(6);
+        ";
+        assert_eq!(run_printing(source), "false\nfalse\nnil\n2\n2\n3\n3\n5\n6\n6\n");
+    }
+
+    #[test]
+    fn index_out_of_range_is_a_runtime_error() {
+        let mut vm = VM::new();
+        let error = vm
+            .interpret(compile_source("var a = [1, 2];\na[2];"))
+            .unwrap_err();
+
+        assert_eq!(error.message, "Index 2 out of range for length 2.");
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn index_set_writes_into_the_list() {
+        let mut vm = VM::new();
+        vm.interpret(compile_source("var a = [1, 2];\na[0] = 5;\nvar b = a[0] + a[1];"))
+            .unwrap();
+
+        assert_eq!(global_number(&vm, "b"), 7.0);
+    }
+
+    #[test]
+    fn ternary_picks_a_branch() {
+        let mut vm = VM::new();
+        vm.interpret(compile_source("var a = true ? 1 : 2;\nvar b = nil ? 1 : 2;"))
+            .unwrap();
+
+        assert_eq!(global_number(&vm, "a"), 1.0);
+        assert_eq!(global_number(&vm, "b"), 2.0);
+
+        assert_eq!(run_printing("print true ? 1 : 2;\nprint false ? 1 : false ? 2 : 3;"), "1\n3\n");
+    }
+
+    #[test]
+    fn runtime_error_can_be_boxed_as_std_error() {
+        let error: Box<dyn std::error::Error> = Box::new(RuntimeError {
+            message: "Operands must be numbers.".to_string(),
+            line: 4,
+            line_text: None,
+            col: 0,
+            trace: Vec::new(),
+        });
+
+        assert_eq!(error.to_string(), "Operands must be numbers.\n[line 4] in script");
+    }
+
+    #[test]
+    fn tail_recursion_does_not_overflow() {
+        let source = "
+            fun count(n) { return n < 1 ? 0 : count(n - 1); }
+            var r = count(10000);
+        ";
+        let mut vm = VM::new();
+        vm.interpret(compile_source(source)).unwrap();
+
+        assert_eq!(global_number(&vm, "r"), 0.0);
+    }
+
+    #[test]
+    fn deep_plain_recursion_overflows() {
+        let source = "
+            fun count(n) { return n < 1 ? 0 : 1 + count(n - 1); }
+            count(10000);
+        ";
+        let error = VM::new().interpret(compile_source(source)).unwrap_err();
+
+        assert_eq!(error.message, "Stack overflow.");
+    }
+
+    #[test]
+    fn modulo_computes_the_remainder() {
+        let mut vm = VM::new();
+        vm.interpret(compile_source("var r = 7 % 3;")).unwrap();
+
+        assert_eq!(global_number(&vm, "r"), 1.0);
+    }
+
+    #[test]
+    fn modulo_by_zero_is_a_runtime_error() {
+        let error = VM::new().interpret(compile_source("1 % 0;")).unwrap_err();
+
+        assert_eq!(error.message, "Modulo by zero.");
+    }
+
+    #[test]
+    fn bitwise_operators_work_on_integers() {
+        let mut vm = VM::new();
+        vm.interpret(compile_source("var a = 6 & 3;\nvar b = 1 << 4;\nvar c = ~0 ^ 1;"))
+            .unwrap();
+
+        assert_eq!(global_number(&vm, "a"), 2.0);
+        assert_eq!(global_number(&vm, "b"), 16.0);
+        assert_eq!(global_number(&vm, "c"), -2.0);
+    }
+
+    #[test]
+    fn bitwise_on_fractions_is_a_runtime_error() {
+        let error = VM::new().interpret(compile_source("1.5 | 1;")).unwrap_err();
+
+        assert_eq!(error.message, "Operands must be integers.");
+    }
+
+    #[test]
+    fn switch_runs_only_the_matching_case() {
+        let source = "
+            var r;
+            switch (2) {
+                case 1: r = 10;
+                case 2: r = 20;
+                default: r = 30;
+            }
+            var d;
+            switch (5) {
+                case 1: d = 10;
+                default: d = 30;
+            }
+        ";
+        let mut vm = VM::new();
+        vm.interpret(compile_source(source)).unwrap();
+
+        assert_eq!(global_number(&vm, "r"), 20.0);
+        assert_eq!(global_number(&vm, "d"), 30.0);
+    }
+
+    #[test]
+    fn break_and_continue_leave_and_restart_loops() {
+        let source = "
+            var i = 0;
+            var skipped = 0;
+            while (i < 10) {
+                i = i + 1;
+                continue;
+                skipped = 1;
+            }
+            var j = 0;
+            while (true) {
+                var local = 1;
+                j = j + local;
+                break;
+            }
+        ";
+        let mut vm = VM::new();
+        vm.interpret(compile_source(source)).unwrap();
+
+        assert_eq!(global_number(&vm, "i"), 10.0);
+        assert_eq!(global_number(&vm, "skipped"), 0.0);
+        assert_eq!(global_number(&vm, "j"), 1.0);
+        // the local was popped on the way out of the loop
+        assert_eq!(vm.stack.len(), 0);
+    }
+
+    #[test]
+    fn print_writes_the_expression_to_the_output() {
+        let script = compile_source("print 1 + 2;");
+        assert!(matches!(script.chunk.code[..4], [Op::Constant(_), Op::Constant(_), Op::Add, Op::Print]));
+
+        assert_eq!(run_printing("print 1 + 2;"), "3\n");
+    }
+
+    #[test]
+    fn print_and_disassembly_format_numbers_alike() {
+        assert_eq!(run_printing("print 1.0;\nprint \"1.0\";"), "1\n1.0\n");
+
+        let script = compile_source("print 1.0;\nprint \"1.0\";");
+        let listing = crate::bytecode_interpreter::dis_chunk(&script.chunk, "script");
+        assert!(listing.contains("OP_CONSTANT 1 (idx=0)"));
+        // strings are only quoted in the disassembly
+        assert!(listing.contains("OP_CONSTANT \"1.0\" (idx=1)"));
+    }
+
+    #[test]
+    fn folded_chunks_print_the_same() {
+        let source = "print 1 < 2;\nprint nil == false;\nprint \"a\" == \"a\";";
+        let mut script = compile_source(source);
+        script.chunk.fold_constants();
+
+        let output = SharedOutput::default();
+        VM::new().with_output(output.clone()).interpret(script).unwrap();
+        assert_eq!(String::from_utf8(output.0.borrow().clone()).unwrap(), run_printing(source));
+        assert_eq!(run_printing(source), "true\nfalse\ntrue\n");
+    }
+
+    #[test]
+    fn print_formats_values_like_lox() {
+        assert_eq!(run_printing("print 5;"), "5\n");
+        assert_eq!(run_printing("print 2.5;"), "2.5\n");
+        assert_eq!(run_printing("print nil;"), "nil\n");
+        assert_eq!(run_printing("print true;"), "true\n");
+        assert_eq!(run_printing("print \"hi\";"), "hi\n");
+    }
+
+    #[test]
+    fn runtime_errors_report_the_failing_line() {
+        let source = "var a = 1;\nvar b = \"two\";\nvar c = a - b;\n";
+        let mut vm = VM::new();
+        let error = vm.interpret(compile_source(source)).unwrap_err();
+
+        assert_eq!(error.message, "Operands must be numbers.");
+        assert_eq!(error.line, 3);
+        assert_eq!(error.trace, vec!["[line 3] in script"]);
+    }
+
+    #[test]
+    fn runtime_errors_show_the_failing_source_line() {
+        let source = "var a = 1;\nprint a + nil;\n";
+        let error = VM::new().interpret_with_source(compile_source(source), source).unwrap_err();
+
+        assert_eq!(error.line_text.as_deref(), Some("print a + nil;"));
+        assert_eq!(
+            error.to_string(),
+            "Operands must be two numbers or two strings, got number and nil.\nprint a + nil;\n      ^\n[line 2] in script"
+        );
+
+        // the caret goes under where the failing expression starts
+        let source = "var s = \"é\";\nprint -s;\n";
+        let error = VM::new().interpret_with_source(compile_source(source), source).unwrap_err();
+        assert_eq!((error.line, error.col), (2, 7));
+
+        // the caret keeps the tabs of the line it sits under
+        let source = "\tprint -nil;";
+        let error = VM::new().interpret_with_source(compile_source(source), source).unwrap_err();
+        assert_eq!(error.col, 8);
+        assert_eq!(error.to_string(), "Operand must be a number.\n\tprint -nil;\n\t      ^\n[line 1] in script");
+
+        // without the source there is nothing to show
+        let error = VM::new().interpret(compile_source(source)).unwrap_err();
+        assert!(error.line_text.is_none());
+    }
+
+    #[test]
+    fn runtime_errors_carry_a_stack_trace() {
+        let source = "fun inner() {\n  return -nil;\n}\nfun outer() {\n  return inner() + 1;\n}\nouter();\n";
+        let mut vm = VM::new();
+        let error = vm.interpret(compile_source(source)).unwrap_err();
+
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.to_string(),
+            "Operand must be a number.\n[line 2] in inner()\n[line 5] in outer()\n[line 7] in script"
+        );
+    }
+
+    #[test]
+    fn lambdas_are_labelled_the_same_everywhere() {
+        assert_eq!(run_printing("fun f() {} print f; print lambda () -> 1;"), "<fn f>\n<lambda>\n");
+
+        let error = VM::new().interpret(compile_source("var f = lambda () -> -nil;\nf();")).unwrap_err();
+        assert_eq!(error.trace, vec!["[line 1] in lambda()", "[line 2] in script"]);
+    }
+
+    #[test]
+    fn only_functions_can_be_called() {
+        // there are no instances yet, so a class can't be called either
+        for source in ["var a = 1; a();", "class Foo {} Foo();"] {
+            let error = VM::new().interpret(compile_source(source)).unwrap_err();
+            assert_eq!(error.message, "Can only call functions.", "{}", source);
+        }
+    }
+
+    #[test]
+    fn class_declaration_binds_its_methods() {
+        let mut vm = VM::new();
+        vm.interpret(compile_source("class Foo { bar() { return 1; } }")).unwrap();
+
+        match vm.global("Foo") {
+            Some(Value::Class(class)) => {
+                assert_eq!(class.name, "Foo");
+                assert!(class.methods.borrow().contains_key("bar"));
+            },
+            other => panic!("expected a class, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reset_keeps_globals_for_the_next_run() {
+        let mut vm = VM::new();
+        vm.interpret(compile_source("var a = 1;")).unwrap();
+        vm.push(Value::Number(2.0));
+
+        vm.reset();
+        assert!(vm.stack.is_empty());
+        assert_eq!(global_number(&vm, "a"), 1.0);
+
+        vm.interpret(compile_source("var b = a + 1;")).unwrap();
+        assert_eq!(global_number(&vm, "b"), 2.0);
+
+        vm.clear_globals();
+        assert!(vm.global("a").is_none());
+    }
+
+    #[test]
+    fn globals_can_be_defined_read_and_reassigned() {
+        assert_eq!(run_printing("var x = 1;\nprint x;\nx = x + 1;\nprint x;\nvar y;\nprint y;"), "1\n2\nnil\n");
+    }
+
+    #[test]
+    fn undefined_globals_are_runtime_errors() {
+        let mut vm = VM::new();
+        let error = vm.interpret(compile_source("print 1;\nprint missing;")).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'missing'.");
+        assert_eq!(error.line, 2);
+
+        // assigning doesn't define it either
+        let error = vm.interpret(compile_source("missing = 1;")).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'missing'.");
+        assert!(vm.global("missing").is_none());
+    }
+
+    #[test]
+    fn nested_scopes_shadow_and_restore_variables() {
+        let source = "
+            var a = \"global\";
+            {
+                var a = \"outer\";
+                {
+                    var a = \"inner\";
+                    print a;
+                    a = \"changed\";
+                    print a;
+                }
+                print a;
+            }
+            print a;
+        ";
+        assert_eq!(run_printing(source), "inner\nchanged\nouter\nglobal\n");
+    }
+
+    #[test]
+    fn del_removes_a_global() {
+        let mut vm = VM::new();
+        let error = vm
+            .interpret(compile_source("var a = 1;\ndel a;\nprint a;"))
+            .unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'a'.");
+        assert_eq!(error.line, 3);
+
+        let error = vm.interpret(compile_source("del b;")).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'b'.");
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn cached_globals_see_reassignment_and_redefinition() {
+        let source = "
+            var a = 1;
+            fun get() { return a; }
+            var first = get();
+            a = 2;
+            var second = get();
+            var a = 3;
+            var third = get();
+        ";
+        let mut vm = VM::new();
+        vm.interpret(compile_source(source)).unwrap();
+
+        assert_eq!(global_number(&vm, "first"), 1.0);
+        assert_eq!(global_number(&vm, "second"), 2.0);
+        assert_eq!(global_number(&vm, "third"), 3.0);
+
+        // the slot outlives a del, reads through it fail again
+        let error = vm.interpret(compile_source("del a;\nget();")).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'a'.");
+    }
+
+    #[test]
+    fn hot_loop_over_globals() {
+        let source = "
+            var i = 0;
+            var sum = 0;
+            while (i < 100000) {
+                sum = sum + i;
+                i = i + 1;
+            }
+        ";
+        let mut vm = VM::new();
+        vm.interpret(compile_source(source)).unwrap();
+
+        assert_eq!(global_number(&vm, "i"), 100000.0);
+        assert_eq!(global_number(&vm, "sum"), 4999950000.0);
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run the binary with `args`, feeding `source` on stdin
+fn run(args: &[&str], source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_test_cir"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(source.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Write `source` to a script file of its own in the temp dir
+fn script(name: &str, source: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("test_cir_{}_{}.lox", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+#[test]
+fn dump_prints_only_the_listing_and_the_program_output() {
+    let output = run(&["--dump"], "print 1 + 2;");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(stdout, format!("{}\n3\n", test_cir::dump("print 1 + 2;").unwrap()));
+}
+
+#[test]
+fn optimize_folds_constants_before_running() {
+    let output = run(&["--dump", "--optimize"], "print true and 1 + 2;");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success());
+    assert!(!stdout.contains("OP_ADD"));
+    assert!(!stdout.contains("OP_JUMP"));
+    assert!(stdout.ends_with("\n3\n"));
+}
+
+#[test]
+fn repl_keeps_globals_and_goes_on_after_errors() {
+    let output = run(&["--repl"], "var a = 1;\nprint a + 1;\nprint b;\nprint a;\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(stdout, "> > 2\n> > 1\n> \n");
+    assert!(stderr.contains("Undefined variable 'b'."));
+}
+
+#[test]
+fn scripts_run_from_a_file() {
+    let path = script("ok", "var a = 2;\nprint a * 21;\n");
+    let output = run(&[path.to_str().unwrap()], "");
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "42\n");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn scripts_exit_like_clox_on_errors() {
+    let path = script("compile_error", "print ;\n");
+    assert_eq!(run(&[path.to_str().unwrap()], "").status.code(), Some(65));
+    std::fs::remove_file(path).unwrap();
+
+    let path = script("scan_error", "print @;\n");
+    assert_eq!(run(&[path.to_str().unwrap()], "").status.code(), Some(65));
+    std::fs::remove_file(path).unwrap();
+
+    let path = script("runtime_error", "print -nil;\n");
+    let output = run(&[path.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(70));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Operand must be a number."));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn missing_scripts_are_reported() {
+    let output = run(&["/no/such/script.lox"], "");
+
+    assert_eq!(output.status.code(), Some(74));
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("Could not read '/no/such/script.lox'"));
+}
@@ -0,0 +1,61 @@
+use test_cir::{compile, compile_optimized, interpret, Error, Op};
+
+#[test]
+fn interpret_runs_a_valid_program() {
+    assert!(interpret("var a = 1 + 2;\nvar b = [a, a];\nb[0] = a * 2;").is_ok());
+}
+
+#[test]
+fn interpret_accepts_empty_input() {
+    assert!(interpret("").is_ok());
+    assert!(interpret("\n\n").is_ok());
+}
+
+#[test]
+fn interpret_reports_scan_errors() {
+    assert!(matches!(interpret("var a = @;"), Err(Error::Scan(_))));
+}
+
+#[test]
+fn compile_optimized_folds_constant_expressions() {
+    let source = "print (1 < 2 and !false) or x;";
+    assert!(compile(source).unwrap().chunk.code.iter().any(|op| matches!(op, Op::JumpIfFalse(_))));
+
+    let code = compile_optimized(source).unwrap().chunk.code;
+    assert!(matches!(code[..2], [Op::True, Op::Print]));
+}
+
+#[test]
+fn scan_errors_can_be_inspected() {
+    let errors = match interpret("var a = 1;\nvar b = @;") {
+        Err(Error::Scan(errors)) => errors,
+        other => panic!("expected scan errors, got {:?}", other),
+    };
+
+    let error = &errors[0];
+    assert_eq!(error.info, "Scanner can't handle: @");
+    assert_eq!((error.line, error.col), (2, 9));
+    assert_eq!(error.line_text, "var b = @;");
+    assert_eq!((error.start_byte, error.end_byte), (19, 20));
+}
+
+#[test]
+fn interpret_reports_compile_errors() {
+    assert!(matches!(interpret("1 = 2;"), Err(Error::Compile(_))));
+}
+
+#[test]
+fn interpret_reports_runtime_errors() {
+    match interpret("var a = [1];\na[3];") {
+        Err(Error::Runtime(err)) => assert_eq!(err.line, 2),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn runtime_errors_show_the_source_line() {
+    let err = interpret("var a = \"one\";\nvar b = -a;").unwrap_err();
+    let message = err.to_string();
+
+    assert!(message.contains("\nvar b = -a;\n"), "{}", message);
+}